@@ -0,0 +1,76 @@
+//! Host-automatable parameters, which can be read from Koto.
+use std::sync::{
+    atomic::{AtomicU16, Ordering},
+    Arc,
+};
+
+use nih_plug::prelude::*;
+
+pub(crate) const NUM_MACROS: usize = 16;
+
+/// The macro parameter. The macros are nested as an array, so the IDs are `macro_1`, `macro_2`, etc.
+#[derive(Params)]
+pub(crate) struct MacroParams {
+    #[id = "macro"]
+    pub(crate) value: FloatParam,
+}
+
+impl MacroParams {
+    /// Makes the bank of macros, which write their values to the `values`.
+    pub(crate) fn bank(values: Arc<MacroValues>) -> [Self; NUM_MACROS] {
+        std::array::from_fn(|index| {
+            let values = values.clone();
+
+            Self {
+                value: FloatParam::new(
+                    format!("Macro {}", index + 1),
+                    0.0,
+                    FloatRange::Linear { min: 0.0, max: 1.0 },
+                )
+                .with_callback(Arc::new(move |value| values.set(index, value))),
+            }
+        })
+    }
+}
+
+/// The latest macro values. The parameter callbacks can be called on any thread (and for each
+/// sample), so the changes are only marked here and reported to the interpreter once per block.
+pub(crate) struct MacroValues {
+    values: [AtomicF32; NUM_MACROS],
+    // a bit per macro
+    changed: AtomicU16,
+}
+
+impl Default for MacroValues {
+    fn default() -> Self {
+        Self {
+            values: std::array::from_fn(|_| AtomicF32::new(0.0)),
+            changed: AtomicU16::new(0),
+        }
+    }
+}
+
+impl MacroValues {
+    /// The value of the macro by its index (from 0).
+    pub(crate) fn get(&self, index: usize) -> Option<f32> {
+        self.values
+            .get(index)
+            .map(|value| value.load(Ordering::Relaxed))
+    }
+
+    fn set(&self, index: usize, value: f32) {
+        if let Some(slot) = self.values.get(index) {
+            slot.store(value, Ordering::Relaxed);
+            self.changed.fetch_or(1 << index, Ordering::Relaxed);
+        }
+    }
+
+    /// Takes the indices and the values of the macros changed since the last call.
+    pub(crate) fn take_changed(&self) -> impl Iterator<Item = (usize, f32)> + '_ {
+        let changed = self.changed.swap(0, Ordering::Relaxed);
+
+        (0..NUM_MACROS)
+            .filter(move |index| changed & (1 << index) != 0)
+            .filter_map(|index| self.get(index).map(|value| (index, value)))
+    }
+}
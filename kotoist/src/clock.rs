@@ -0,0 +1,87 @@
+//! Internal clock, which drives the playback when the host doesn't provide the beat position.
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use nih_plug::prelude::AtomicF32;
+
+use crate::orchestrator::Transport;
+
+const DEFAULT_TEMPO: f32 = 120.0;
+
+/// The clock state, which is shared between the audio thread and the interpreter.
+pub(crate) struct Clock {
+    tempo: AtomicF32,
+    is_playing: AtomicBool,
+    should_rewind: AtomicBool,
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self {
+            tempo: AtomicF32::new(DEFAULT_TEMPO),
+            is_playing: AtomicBool::new(false),
+            should_rewind: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Clock {
+    pub(crate) fn set_tempo(&self, bpm: f32) {
+        self.tempo.store(bpm.max(1.0), Ordering::Relaxed);
+    }
+
+    pub(crate) fn tempo(&self) -> f32 {
+        self.tempo.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn start(&self) {
+        self.is_playing.store(true, Ordering::Relaxed);
+    }
+
+    /// Stops the clock and rewinds it to the beginning.
+    pub(crate) fn stop(&self) {
+        self.is_playing.store(false, Ordering::Relaxed);
+        self.should_rewind.store(true, Ordering::Relaxed);
+    }
+
+    pub(crate) fn is_playing(&self) -> bool {
+        self.is_playing.load(Ordering::Relaxed)
+    }
+}
+
+/// The position of the internal clock. It's owned by the audio thread.
+#[derive(Debug, Default)]
+pub(crate) struct ClockPosition {
+    samples: f64,
+    beats: f64,
+}
+
+impl ClockPosition {
+    /// Applies the changes requested from the interpreter. Should be called at the beginning of the
+    /// block.
+    pub(crate) fn sync(&mut self, clock: &Clock) {
+        if clock.should_rewind.swap(false, Ordering::Relaxed) {
+            *self = Self::default();
+        }
+    }
+
+    pub(crate) fn beats(&self) -> f64 {
+        self.beats
+    }
+
+    pub(crate) fn transport(&self, clock: &Clock, sample_rate: f32) -> Transport {
+        let beat_length = sample_rate as f64 * 60.0 / clock.tempo() as f64;
+
+        Transport::new(self.samples, beat_length).with_bars((4, 4), self.beats, None)
+    }
+
+    /// Moves the position by the block size, if the clock is running.
+    pub(crate) fn advance(&mut self, clock: &Clock, block_size: usize, sample_rate: f32) {
+        if !clock.is_playing() {
+            return;
+        }
+
+        let beats_per_sample = clock.tempo() as f64 / 60.0 / sample_rate as f64;
+        self.samples += block_size as f64;
+        self.beats += block_size as f64 * beats_per_sample;
+    }
+}
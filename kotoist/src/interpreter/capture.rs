@@ -0,0 +1,187 @@
+//! Recording of the incoming notes into a phrase, which can be played back by a pattern.
+
+// the notes starting closer than this (in beats) are considered a chord, if there's no quantization
+const CHORD_TOLERANCE: f64 = 1.0 / 32.0;
+
+#[derive(Debug, Default)]
+pub(crate) struct Capture {
+    is_recording: bool,
+    notes: Vec<CapturedNote>,
+    // the latest beat position seen while recording
+    last_position: f64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct CapturedNote {
+    note: u8,
+    velocity: f32,
+    start: f64,
+    end: Option<f64>,
+}
+
+/// The captured phrase, all the lists have the same length.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Phrase {
+    /// The notes of each step, no notes is a rest.
+    pub(crate) degree: Vec<Vec<u8>>,
+    /// In beats.
+    pub(crate) dur: Vec<f64>,
+    /// Relative to `dur`, as in the pattern.
+    pub(crate) length: Vec<f64>,
+    pub(crate) amp: Vec<f64>,
+}
+
+impl Capture {
+    /// Starts a new recording, the previous one is discarded.
+    pub(crate) fn start(&mut self) {
+        *self = Self {
+            is_recording: true,
+            ..Default::default()
+        };
+    }
+
+    /// Records the note-on (velocity > 0) or the note-off at the beat position.
+    pub(crate) fn record(&mut self, note: u8, velocity: f32, position: f64) {
+        if !self.is_recording {
+            return;
+        }
+
+        self.last_position = self.last_position.max(position);
+
+        if velocity > 0.0 {
+            self.notes.push(CapturedNote {
+                note,
+                velocity,
+                start: position,
+                end: None,
+            });
+        } else if let Some(captured) = self
+            .notes
+            .iter_mut()
+            .rev()
+            .find(|captured| captured.note == note && captured.end.is_none())
+        {
+            captured.end = Some(position);
+        }
+    }
+
+    /// Stops the recording and makes the phrase of the captured notes. The onsets and the ends of
+    /// the notes are quantized to the grid in beats, if it's set.
+    pub(crate) fn stop(&mut self, quantization: Option<f64>) -> Phrase {
+        self.is_recording = false;
+
+        let quantization = quantization.filter(|q| *q > 0.0);
+        let quantize = |value: f64| match quantization {
+            Some(q) => (value / q).round() * q,
+            None => value,
+        };
+        let tolerance = if quantization.is_some() {
+            1e-9
+        } else {
+            CHORD_TOLERANCE
+        };
+
+        let last_position = self.last_position;
+        let mut notes: Vec<CapturedNote> = self
+            .notes
+            .drain(..)
+            .map(|captured| {
+                // the notes still held are released at the stop
+                let end = captured.end.unwrap_or(last_position);
+                let start = quantize(captured.start);
+                let end = quantize(end).max(start + quantization.unwrap_or(0.0));
+
+                CapturedNote {
+                    start,
+                    end: Some(end),
+                    ..captured
+                }
+            })
+            .collect();
+        notes.sort_by(|a, b| a.start.total_cmp(&b.start));
+
+        let mut chords: Vec<Vec<CapturedNote>> = Vec::new();
+        for captured in notes {
+            match chords.last_mut() {
+                Some(chord) if captured.start - chord[0].start < tolerance => chord.push(captured),
+                _ => chords.push(vec![captured]),
+            }
+        }
+
+        let mut phrase = Phrase::default();
+        let first_start = match chords.first() {
+            Some(chord) => chord[0].start,
+            None => return phrase,
+        };
+
+        // the phrase keeps its position within the beat
+        let leading_rest = first_start - first_start.floor();
+        if leading_rest > tolerance {
+            phrase.push(Vec::new(), leading_rest, 1.0, 0.0);
+        }
+
+        for (index, chord) in chords.iter().enumerate() {
+            let start = chord[0].start;
+            let note_length = chord
+                .iter()
+                .map(|captured| captured.end.unwrap_or(start) - start)
+                .fold(0.0, f64::max);
+            let dur = match chords.get(index + 1) {
+                Some(next) => next[0].start - start,
+                None => note_length.max(quantization.unwrap_or(0.0)),
+            };
+            let length = if dur > 0.0 { note_length / dur } else { 1.0 };
+            let amp = chord
+                .iter()
+                .map(|captured| f64::from(captured.velocity))
+                .fold(0.0, f64::max);
+            let mut degree: Vec<u8> = chord.iter().map(|captured| captured.note).collect();
+            degree.sort_unstable();
+            degree.dedup();
+
+            phrase.push(degree, dur, length, amp);
+        }
+
+        phrase
+    }
+}
+
+impl Phrase {
+    fn push(&mut self, degree: Vec<u8>, dur: f64, length: f64, amp: f64) {
+        self.degree.push(degree);
+        self.dur.push(dur);
+        self.length.push(length);
+        self.amp.push(amp);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_capture() {
+        let mut capture = Capture::default();
+        // nothing is recorded before the start
+        capture.record(60, 1.0, 0.0);
+        capture.start();
+        // a chord slightly off the grid
+        capture.record(60, 0.5, 1.52);
+        capture.record(64, 1.0, 1.49);
+        capture.record(60, 0.0, 2.0);
+        capture.record(64, 0.0, 2.01);
+        // a note, which is still held at the stop
+        capture.record(67, 0.8, 2.5);
+        capture.record(72, 0.0, 3.0);
+
+        let phrase = capture.stop(Some(0.5));
+        assert_eq!(phrase.degree, vec![vec![], vec![60, 64], vec![67]]);
+        assert_eq!(phrase.dur, vec![0.5, 1.0, 0.5]);
+        assert_eq!(phrase.length, vec![1.0, 0.5, 1.0]);
+        assert_eq!(phrase.amp, vec![0.0, 1.0, f64::from(0.8_f32)]);
+
+        // the recording is stopped
+        capture.record(60, 1.0, 4.0);
+        assert_eq!(capture.stop(None), Phrase::default());
+    }
+}
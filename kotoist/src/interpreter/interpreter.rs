@@ -1,22 +1,61 @@
-use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
 
 use koto::prelude::*;
-use koto_random::make_module as make_random_module;
+use nih_plug::prelude::AtomicF32;
 
-use super::kotoist_module::{self, Callbacks};
+use super::capture::Capture;
+use super::kotoist_module::{self, Callbacks, ModuleContext};
+use super::patterns;
+use super::random_module::make_module as make_random_module;
+use super::scheduler::{DueAction, Scheduler};
+use crate::automation::MacroValues;
+use crate::clock::Clock;
+use crate::midi::{MidiIn, MidiState};
 use crate::orchestrator::Orchestrator;
+use crate::parameters::Snippet;
 use crate::pipe::{Message as PipeMessage, PipeIn};
+use crate::status::PlaybackStatus;
 
-const KOTO_LIB_CODE: &str = include_str!("../../koto/pattern.koto");
+pub(super) const KOTO_LIB_CODE: &str = include_str!("../../koto/pattern.koto");
+
+/// The plugin state shared with the interpreter.
+pub(crate) struct SharedState {
+    pub(crate) orchestrator: Arc<Mutex<Orchestrator>>,
+    pub(crate) snippets: Arc<RwLock<Vec<Snippet>>>,
+    pub(crate) clock: Arc<Clock>,
+    pub(crate) midi_in: MidiIn,
+    pub(crate) midi_state: Arc<MidiState>,
+    pub(crate) macro_values: Arc<MacroValues>,
+    /// The beat position of the transport.
+    pub(crate) cursor: Arc<AtomicF32>,
+    pub(crate) playback: Arc<PlaybackStatus>,
+    /// The seed of the random numbers of the players.
+    pub(crate) seed: Arc<RwLock<u64>>,
+}
 
 pub(crate) struct Interpreter {
     koto: Koto,
     pipe_in: PipeIn,
+    orchestrator: Arc<Mutex<Orchestrator>>,
+    seed: Arc<RwLock<u64>>,
     callbacks: Arc<Mutex<Callbacks>>,
+    capture: Arc<Mutex<Capture>>,
+    scheduler: Arc<Mutex<Scheduler>>,
 }
 
 impl Interpreter {
-    pub(crate) fn new(orchestrator: Arc<Mutex<Orchestrator>>, pipe_in: PipeIn) -> Self {
+    pub(crate) fn new(shared: SharedState, pipe_in: PipeIn) -> Self {
+        let SharedState {
+            orchestrator,
+            snippets,
+            clock,
+            midi_in,
+            midi_state,
+            macro_values,
+            cursor,
+            playback,
+            seed,
+        } = shared;
         let mut koto = Koto::with_settings(
             KotoSettings {
                 run_tests: cfg!(debug_assertions),
@@ -27,17 +66,39 @@ impl Interpreter {
             .with_stderr(StdErr::from(&pipe_in)),
         );
         let callbacks = Arc::new(Mutex::new(Callbacks::default()));
+        let capture = Arc::new(Mutex::new(Capture::default()));
+        let scheduler = Arc::new(Mutex::new(Scheduler::default()));
 
         koto.prelude().insert(
             "kotoist",
-            kotoist_module::make_module(orchestrator, callbacks.clone(), pipe_in.clone()),
+            kotoist_module::make_module(ModuleContext {
+                orchestrator: orchestrator.clone(),
+                snippets,
+                callbacks: callbacks.clone(),
+                pipe_in: pipe_in.clone(),
+                clock,
+                midi_in,
+                midi_state,
+                capture: capture.clone(),
+                scheduler: scheduler.clone(),
+                cursor,
+                playback,
+                macro_values,
+                seed: seed.clone(),
+            }),
         );
         koto.prelude().insert("random", make_random_module());
 
         koto.compile(
             CompileArgs::new(
                 "from kotoist import midiout, on_load, on_midiin, \
-                    on_midiincc, on_play, on_pause, print_scales",
+                    on_midiincc, on_pitchbend, on_aftertouch, on_program_change, on_clock, \
+                    on_transport, on_event, on_sysex, on_macro, on_play, on_pause, on_stop, \
+                    on_tempo_change, on_jump, on_bar, on_beat, print_scales, set_tempo, start, \
+                    stop, tempo_ratio, tempo_curve, now, note_on, note_off, note, cc, sysex, \
+                    midithru, held_notes, ccin, macro, macro_value, capture_start, capture_stop, \
+                    bind_snippet, at, sched, routine, cancel, tempo, is_playing, time_signature, \
+                    players, seed",
             )
             .export_top_level_ids(true),
         )
@@ -52,9 +113,19 @@ impl Interpreter {
             panic!("evaluating the koto pattern library should not fail");
         }
 
+        // the native patterns replace the Koto ones, which are kept as the reference
+        koto.compile(CompileArgs::new(&patterns::import_code()).export_top_level_ids(true))
+            .expect("native patterns import should compile");
+        koto.run()
+            .expect("importing native patterns should not fail");
+
         Self {
             koto,
+            orchestrator,
+            seed,
             callbacks,
+            capture,
+            scheduler,
             pipe_in,
         }
     }
@@ -66,13 +137,29 @@ impl Interpreter {
         self.handle_koto_result(result);
     }
 
+    /// Seeds the `random` module with the global seed, so the numbers drawn outside of the players
+    /// are the same each time the project is loaded.
+    pub(crate) fn seed_random(&mut self) {
+        let seed = *self.seed.read().unwrap();
+        let function = match self.koto.prelude().get("random") {
+            Some(KValue::Map(module)) => module.get("seed"),
+            _ => None,
+        };
+        if let Some(function) = function {
+            let result = self.koto.call_function(function, &[(seed as i64).into()]);
+            self.handle_koto_result(result);
+        }
+    }
+
     /// Dispatch `on_load` callback.
     pub(crate) fn on_load(&mut self) {
         self.dispatch_callback(&[], |cbs| cbs.load.clone())
     }
 
-    /// Dispatch `on_midiin` callback.
-    pub(crate) fn on_midiin(&mut self, nn: u8, vel: f32, ch: u8) {
+    /// Dispatch `on_midiin` callback. The note is also recorded, if the capture is started.
+    pub(crate) fn on_midiin(&mut self, nn: u8, vel: f32, ch: u8, beat_pos: f64) {
+        self.capture.lock().unwrap().record(nn, vel, beat_pos);
+
         self.dispatch_callback(&[nn.into(), vel.into(), ch.into()], |cbs| {
             cbs.midiin.clone()
         })
@@ -81,7 +168,131 @@ impl Interpreter {
     /// Dispatch `on_midiincc` callback.
     pub(crate) fn on_midiincc(&mut self, cc: u8, vel: f32, ch: u8) {
         self.dispatch_callback(&[cc.into(), vel.into(), ch.into()], |cbs| {
-            cbs.midiin.clone()
+            cbs.midiincc.clone()
+        })
+    }
+
+    /// Dispatch `on_pitchbend` callback.
+    pub(crate) fn on_pitchbend(&mut self, value: f32, ch: u8) {
+        self.dispatch_callback(&[value.into(), ch.into()], |cbs| cbs.pitchbend.clone())
+    }
+
+    /// Dispatch `on_aftertouch` callback. The note is `null` for the channel pressure.
+    pub(crate) fn on_aftertouch(&mut self, pressure: f32, ch: u8, nn: Option<u8>) {
+        let nn = nn.map(KValue::from).unwrap_or_default();
+        self.dispatch_callback(&[pressure.into(), ch.into(), nn], |cbs| {
+            cbs.aftertouch.clone()
+        })
+    }
+
+    /// Dispatch `on_program_change` callback.
+    pub(crate) fn on_program_change(&mut self, program: u8, ch: u8) {
+        self.dispatch_callback(&[program.into(), ch.into()], |cbs| {
+            cbs.program_change.clone()
+        })
+    }
+
+    /// Dispatch `on_clock` callback.
+    pub(crate) fn on_clock(&mut self, tick: i64, beat_pos: f64) {
+        self.dispatch_callback(&[tick.into(), beat_pos.into()], |cbs| cbs.clock.clone())
+    }
+
+    /// Calls the functions scheduled by `at` and `sched` and resumes the routines up to the beat
+    /// position. The repeating functions are cancelled, when they return anything but `true`. The
+    /// routines are done, when they don't yield a number of beats to wait.
+    pub(crate) fn run_scheduled(&mut self, beat_pos: f64) {
+        let due = self.scheduler.lock().unwrap().take_due(beat_pos);
+
+        for task in due {
+            let error = match task.action {
+                DueAction::Call {
+                    function,
+                    is_repeating,
+                } => match self.koto.call_function(function, &[]) {
+                    Ok(KValue::Bool(true)) => continue,
+                    Ok(_) if !is_repeating => continue,
+                    Ok(_) => None,
+                    Err(err) => Some(err.to_string()),
+                },
+                DueAction::Resume(mut routine) => match routine.next() {
+                    Some(KIteratorOutput::Value(KValue::Number(wait)))
+                        if f64::from(wait) >= 0.0 =>
+                    {
+                        let due = task.due + f64::from(wait);
+                        self.scheduler.lock().unwrap().resume(task.id, due);
+                        continue;
+                    }
+                    Some(KIteratorOutput::Error(err)) => Some(err.to_string()),
+                    Some(_) => Some(
+                        "kotoist.routine: the routine should yield the number of beats to wait"
+                            .to_string(),
+                    ),
+                    None => None,
+                },
+            };
+
+            self.scheduler.lock().unwrap().cancel(task.id);
+            if let Some(error) = error {
+                self.pipe_in
+                    .send(PipeMessage::Error(format!("Error: {}", error)));
+            }
+        }
+    }
+
+    /// Moves the scheduled repeating tasks along with the transport, which jumped to the beat
+    /// position.
+    pub(crate) fn jump_scheduled(&mut self, beat_pos: f64) {
+        self.scheduler.lock().unwrap().jump(beat_pos);
+    }
+
+    /// Passes the events prefetched by the orchestrator through the `on_event` callback and
+    /// returns them to be played, so the audio thread doesn't call it. The orchestrator isn't
+    /// locked meanwhile, so the callback can call the `kotoist` functions. It's removed on error,
+    /// as it would fail for the next events too.
+    pub(crate) fn prefetch_events(&mut self) {
+        let hook = match self.callbacks.try_lock() {
+            Ok(callbacks) => callbacks.event.clone(),
+            Err(_) => return,
+        };
+        let Some(hook) = hook else {
+            return;
+        };
+
+        let mut events = self.orchestrator.lock().unwrap().prefetch();
+        for prefetched in events.iter_mut() {
+            if let Err(e) = hook.apply(&mut self.koto, &mut prefetched.event, &prefetched.player) {
+                self.callbacks.lock().unwrap().event = None;
+                self.orchestrator.lock().unwrap().set_event_hooked(false);
+                self.pipe_in
+                    .send(PipeMessage::Error(format!("Error: {}", e)));
+                break;
+            }
+        }
+        self.orchestrator.lock().unwrap().return_prefetched(events);
+    }
+
+    /// Copies the patterns, which restart on loop, so the copies are ready before the next loop wrap.
+    pub(crate) fn prepare_restarts(&mut self) {
+        self.orchestrator.lock().unwrap().prepare_restarts();
+    }
+
+    /// Dispatch `on_transport` callback.
+    pub(crate) fn on_transport(&mut self, is_playing: bool, beat_pos: f64, tempo: f64) {
+        self.dispatch_callback(&[is_playing.into(), beat_pos.into(), tempo.into()], |cbs| {
+            cbs.transport.clone()
+        })
+    }
+
+    /// Dispatch `on_sysex` callback.
+    pub(crate) fn on_sysex(&mut self, bytes: &[u8]) {
+        let bytes: Vec<KValue> = bytes.iter().map(|byte| (*byte).into()).collect();
+        self.dispatch_callback(&[KList::from_slice(&bytes).into()], |cbs| cbs.sysex.clone())
+    }
+
+    /// Dispatch `on_macro` callback. The macros are numbered from 1 in Koto, as in the host.
+    pub(crate) fn on_macro(&mut self, index: usize, value: f32) {
+        self.dispatch_callback(&[(index + 1).into(), value.into()], |cbs| {
+            cbs.macro_change.clone()
         })
     }
 
@@ -95,6 +306,37 @@ impl Interpreter {
         self.dispatch_callback(&[beat_pos.into(), tempo.into()], |cbs| cbs.play.clone())
     }
 
+    /// Dispatch `on_stop` callback.
+    pub(crate) fn on_stop(&mut self, beat_pos: f64) {
+        self.dispatch_callback(&[beat_pos.into()], |cbs| cbs.stop.clone())
+    }
+
+    /// Dispatch `on_tempo_change` callback.
+    pub(crate) fn on_tempo_change(&mut self, old_tempo: f64, new_tempo: f64) {
+        self.dispatch_callback(&[old_tempo.into(), new_tempo.into()], |cbs| {
+            cbs.tempo_change.clone()
+        })
+    }
+
+    /// Dispatch `on_jump` callback.
+    pub(crate) fn on_jump(&mut self, from_beat_pos: f64, to_beat_pos: f64) {
+        self.dispatch_callback(&[from_beat_pos.into(), to_beat_pos.into()], |cbs| {
+            cbs.jump.clone()
+        })
+    }
+
+    /// Dispatch `on_bar` callback.
+    pub(crate) fn on_bar(&mut self, bar: i64, beat_pos: f64) {
+        self.dispatch_callback(&[bar.into(), beat_pos.into()], |cbs| cbs.bar.clone())
+    }
+
+    /// Dispatch `on_beat` callback.
+    pub(crate) fn on_beat(&mut self, beat: u32, bar: i64, beat_pos: f64) {
+        self.dispatch_callback(&[beat.into(), bar.into(), beat_pos.into()], |cbs| {
+            cbs.beat.clone()
+        })
+    }
+
     fn dispatch_callback<'a>(
         &mut self,
         args: impl Into<CallArgs<'a>>,
@@ -177,3 +419,109 @@ macro_rules! impl_channel {
 
 impl_channel!(StdOut, PipeMessage::Normal, "koto-stdout");
 impl_channel!(StdErr, PipeMessage::Error, "koto-stderr");
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::midi::new_midi_queue;
+    use crate::orchestrator::{EventValue, Modifiers, Transport};
+    use crate::pipe::{new_pipe, PipeOut};
+
+    fn make_interpreter(seed: u64) -> (Interpreter, Arc<Mutex<Orchestrator>>, PipeOut) {
+        let (pipe_in, pipe_out) = new_pipe();
+        let midi_state = Arc::new(MidiState::default());
+        let orchestrator = Arc::new(Mutex::new(Orchestrator::new(
+            pipe_in.clone(),
+            midi_state.clone(),
+        )));
+        let shared = SharedState {
+            orchestrator: orchestrator.clone(),
+            snippets: Default::default(),
+            clock: Default::default(),
+            midi_in: new_midi_queue().0,
+            midi_state,
+            macro_values: Default::default(),
+            cursor: Arc::new(AtomicF32::new(0.0)),
+            playback: Default::default(),
+            seed: Arc::new(RwLock::new(seed)),
+        };
+
+        (Interpreter::new(shared, pipe_in), orchestrator, pipe_out)
+    }
+
+    fn errors(pipe_out: &PipeOut) -> Vec<String> {
+        pipe_out
+            .receiver
+            .try_iter()
+            .filter_map(|message| match message {
+                PipeMessage::Error(error) => Some(error),
+                PipeMessage::Normal(_) => None,
+            })
+            .collect()
+    }
+
+    // the notes played by the orchestrator at the position in samples, a beat is 100 samples
+    fn notes(orchestrator: &mut Orchestrator, position: f64) -> Vec<EventValue> {
+        let transport = Transport::new(position, 100.0);
+        orchestrator
+            .tick(true, &transport, 0, &Modifiers::default())
+            .iter()
+            .flat_map(|event| event.value.clone())
+            .filter(|value| matches!(value, EventValue::Note(_, velocity, _) if *velocity > 0))
+            .collect()
+    }
+
+    #[test]
+    fn test_prefetch_events() {
+        let (mut interpreter, orchestrator, pipe_out) = make_interpreter(0);
+
+        // the hook sees the top-level values and changes the other players
+        interpreter.eval_code(
+            "
+pitch = 42
+on_event |event|
+  tempo_ratio event.player, 2
+  event.pitches = [pitch]
+  event
+midiout {degree: 0}, 0
+",
+        );
+
+        // the player waits for the events, which aren't passed through the hook yet
+        assert!(notes(&mut orchestrator.lock().unwrap(), 0.0).is_empty());
+        interpreter.prefetch_events();
+        let played = notes(&mut orchestrator.lock().unwrap(), 1.0);
+        assert!(matches!(played.as_slice(), [EventValue::Note(42, _, _)]));
+        assert!(errors(&pipe_out).is_empty());
+    }
+
+    #[test]
+    fn test_seed_random() {
+        // loads the project and plays a generator written in Koto
+        let play = |seed: u64| {
+            let (mut interpreter, orchestrator, pipe_out) = make_interpreter(seed);
+            interpreter.seed_random();
+            interpreter.eval_code(
+                "
+noise = ||
+  loop
+    yield (random.number() * 12).floor()
+offset = random.pick [0, 12, 24]
+midiout {degree: noise().each(|n| n + offset), scale: 'chromatic', dur: 0.25}, 0
+",
+            );
+
+            let mut orchestrator = orchestrator.lock().unwrap();
+            let played: Vec<EventValue> = (0..400)
+                .flat_map(|position| notes(&mut orchestrator, position as f64))
+                .collect();
+            assert!(errors(&pipe_out).is_empty());
+            played
+        };
+
+        let played = play(7);
+        assert!(played.len() >= 8);
+        assert_eq!(play(7), played);
+        assert_ne!(play(8), played);
+    }
+}
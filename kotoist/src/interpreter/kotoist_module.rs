@@ -1,15 +1,57 @@
-use std::sync::{Arc, Mutex};
+use std::sync::{atomic::Ordering, Arc, Mutex, RwLock};
 
-use crate::orchestrator::{Orchestrator, Pattern, Scale};
+use super::capture::{Capture, Phrase};
+use super::patterns;
+use super::random_module;
+use super::scheduler::Scheduler;
+use crate::automation::{MacroValues, NUM_MACROS};
+use crate::clock::Clock;
+use crate::midi::{MidiIn, MidiMessage, MidiState, MidiThru, SysEx};
+use crate::orchestrator::{
+    EventHook, Orchestrator, Pattern, PlayerId, PlayerStatus, Quantization, QuantizationUnit,
+    Scale, TempoCurve,
+};
+use crate::parameters::Snippet;
+use crate::scene::{SceneBinding, SceneTrigger};
+use crate::status::PlaybackStatus;
 use koto::{prelude::*, runtime::Result};
+use nih_plug::prelude::AtomicF32;
 
 use crate::pipe::{Message as PipeMessage, PipeIn};
 
-pub(crate) fn make_module(
-    orchestrator: Arc<Mutex<Orchestrator>>,
-    callbacks: Arc<Mutex<Callbacks>>,
-    pipe_in: PipeIn,
-) -> KMap {
+/// The state shared by the module functions with the interpreter and the plugin.
+pub(crate) struct ModuleContext {
+    pub(crate) orchestrator: Arc<Mutex<Orchestrator>>,
+    pub(crate) snippets: Arc<RwLock<Vec<Snippet>>>,
+    pub(crate) callbacks: Arc<Mutex<Callbacks>>,
+    pub(crate) pipe_in: PipeIn,
+    pub(crate) clock: Arc<Clock>,
+    pub(crate) midi_in: MidiIn,
+    pub(crate) midi_state: Arc<MidiState>,
+    pub(crate) capture: Arc<Mutex<Capture>>,
+    pub(crate) macro_values: Arc<MacroValues>,
+    pub(crate) scheduler: Arc<Mutex<Scheduler>>,
+    pub(crate) cursor: Arc<AtomicF32>,
+    pub(crate) playback: Arc<PlaybackStatus>,
+    pub(crate) seed: Arc<RwLock<u64>>,
+}
+
+pub(crate) fn make_module(context: ModuleContext) -> KMap {
+    let ModuleContext {
+        orchestrator,
+        snippets,
+        callbacks,
+        pipe_in,
+        clock,
+        midi_in,
+        midi_state,
+        capture,
+        macro_values,
+        scheduler,
+        cursor,
+        playback,
+        seed,
+    } = context;
     let result = KMap::new();
 
     let cbks = callbacks.clone();
@@ -21,14 +63,105 @@ pub(crate) fn make_module(
         cbks.lock().unwrap().set_midiincc(ctx)
     });
     let cbks = callbacks.clone();
+    result.add_fn("on_pitchbend", move |ctx| {
+        cbks.lock().unwrap().set_pitchbend(ctx)
+    });
+    let cbks = callbacks.clone();
+    result.add_fn("on_aftertouch", move |ctx| {
+        cbks.lock().unwrap().set_aftertouch(ctx)
+    });
+    let cbks = callbacks.clone();
+    result.add_fn("on_program_change", move |ctx| {
+        cbks.lock().unwrap().set_program_change(ctx)
+    });
+    let cbks = callbacks.clone();
+    result.add_fn("on_clock", move |ctx| cbks.lock().unwrap().set_clock(ctx));
+    let cbks = callbacks.clone();
+    result.add_fn("on_transport", move |ctx| {
+        cbks.lock().unwrap().set_transport(ctx)
+    });
+    let cbks = callbacks.clone();
+    result.add_fn("on_sysex", move |ctx| cbks.lock().unwrap().set_sysex(ctx));
+    let cbks = callbacks.clone();
+    result.add_fn("on_macro", move |ctx| cbks.lock().unwrap().set_macro(ctx));
+    let cbks = callbacks.clone();
     result.add_fn("on_pause", move |ctx| cbks.lock().unwrap().set_pause(ctx));
+    let cbks = callbacks.clone();
+    result.add_fn("on_stop", move |ctx| cbks.lock().unwrap().set_stop(ctx));
+    let cbks = callbacks.clone();
+    result.add_fn("on_tempo_change", move |ctx| {
+        cbks.lock().unwrap().set_tempo_change(ctx)
+    });
+    let cbks = callbacks.clone();
+    result.add_fn("on_jump", move |ctx| cbks.lock().unwrap().set_jump(ctx));
+    let cbks = callbacks.clone();
+    result.add_fn("on_bar", move |ctx| cbks.lock().unwrap().set_bar(ctx));
+    let cbks = callbacks.clone();
+    result.add_fn("on_beat", move |ctx| cbks.lock().unwrap().set_beat(ctx));
     result.add_fn("on_play", move |ctx| {
         callbacks.lock().unwrap().set_play(ctx)
     });
     result.add_fn("print_scales", move |ctx| {
         print_scales(ctx, pipe_in.clone())
     });
-    result.add_fn("midiout", move |ctx| midiout(ctx, orchestrator.clone()));
+    let cbks = callbacks.clone();
+    let orch = orchestrator.clone();
+    result.add_fn("on_event", move |ctx| on_event(ctx, &cbks, &orch));
+    let orch = orchestrator.clone();
+    result.add_fn("tempo_ratio", move |ctx| tempo_ratio(ctx, &orch));
+    let orch = orchestrator.clone();
+    result.add_fn("tempo_curve", move |ctx| tempo_curve(ctx, &orch));
+    let sd = seed.clone();
+    let status = playback.clone();
+    result.add_fn("midiout", move |ctx| {
+        midiout(ctx, orchestrator.clone(), &sd, &status)
+    });
+    result.add_fn("seed", move |ctx| set_seed(ctx, &seed));
+    let clk = clock.clone();
+    result.add_fn("set_tempo", move |ctx| set_tempo(ctx, &clk));
+    let clk = clock.clone();
+    result.add_fn("start", move |ctx| start(ctx, &clk));
+    result.add_fn("stop", move |ctx| stop(ctx, &clock));
+    let midi = midi_in.clone();
+    result.add_fn("note_on", move |ctx| note_on(ctx, &midi));
+    let midi = midi_in.clone();
+    result.add_fn("note_off", move |ctx| note_off(ctx, &midi));
+    let midi = midi_in.clone();
+    result.add_fn("note", move |ctx| note(ctx, &midi));
+    let midi = midi_in.clone();
+    result.add_fn("cc", move |ctx| cc(ctx, &midi));
+    result.add_fn("sysex", move |ctx| sysex(ctx, &midi_in));
+    let midi = midi_state.clone();
+    result.add_fn("midithru", move |ctx| midithru(ctx, &midi.thru));
+    let midi = midi_state.clone();
+    result.add_fn("held_notes", move |ctx| held_notes(ctx, &midi));
+    result.add_fn("ccin", move |ctx| ccin(ctx, &midi_state));
+    let cptr = capture.clone();
+    let macros = macro_values.clone();
+    result.add_fn("macro", move |ctx| macro_iter(ctx, &macros));
+    result.add_fn("macro_value", move |ctx| macro_value(ctx, &macro_values));
+    result.add_fn("capture_start", move |ctx| capture_start(ctx, &cptr));
+    result.add_fn("capture_stop", move |ctx| capture_stop(ctx, &capture));
+    result.add_fn("bind_snippet", move |ctx| bind_snippet(ctx, &snippets));
+    let schd = scheduler.clone();
+    result.add_fn("at", move |ctx| at(ctx, &schd));
+    let schd = scheduler.clone();
+    let crsr = cursor.clone();
+    result.add_fn("sched", move |ctx| sched(ctx, &schd, &crsr));
+    let schd = scheduler.clone();
+    let crsr = cursor.clone();
+    result.add_fn("routine", move |ctx| routine(ctx, &schd, &crsr));
+    result.add_fn("cancel", move |ctx| cancel(ctx, &scheduler));
+    result.add_fn("now", move |ctx| now(ctx, &cursor));
+    let status = playback.clone();
+    result.add_fn("tempo", move |ctx| tempo(ctx, &status));
+    let status = playback.clone();
+    result.add_fn("is_playing", move |ctx| is_playing(ctx, &status));
+    let status = playback.clone();
+    result.add_fn("time_signature", move |ctx| time_signature(ctx, &status));
+    result.add_fn("players", move |ctx| players(ctx, &playback));
+
+    patterns::add_patterns(&result);
 
     result
 }
@@ -38,8 +171,21 @@ pub(crate) struct Callbacks {
     pub(crate) load: Option<KValue>,
     pub(crate) midiin: Option<KValue>,
     pub(crate) midiincc: Option<KValue>,
+    pub(crate) pitchbend: Option<KValue>,
+    pub(crate) aftertouch: Option<KValue>,
+    pub(crate) program_change: Option<KValue>,
+    pub(crate) clock: Option<KValue>,
+    pub(crate) transport: Option<KValue>,
+    pub(crate) sysex: Option<KValue>,
+    pub(crate) macro_change: Option<KValue>,
     pub(crate) pause: Option<KValue>,
     pub(crate) play: Option<KValue>,
+    pub(crate) stop: Option<KValue>,
+    pub(crate) tempo_change: Option<KValue>,
+    pub(crate) jump: Option<KValue>,
+    pub(crate) bar: Option<KValue>,
+    pub(crate) beat: Option<KValue>,
+    pub(crate) event: Option<EventHook>,
 }
 
 impl Callbacks {
@@ -55,14 +201,62 @@ impl Callbacks {
         Self::set_callback(&mut self.midiincc, ctx, "on_midiincc")
     }
 
+    fn set_pitchbend(&mut self, ctx: &mut CallContext) -> Result<KValue> {
+        Self::set_callback(&mut self.pitchbend, ctx, "on_pitchbend")
+    }
+
+    fn set_aftertouch(&mut self, ctx: &mut CallContext) -> Result<KValue> {
+        Self::set_callback(&mut self.aftertouch, ctx, "on_aftertouch")
+    }
+
+    fn set_program_change(&mut self, ctx: &mut CallContext) -> Result<KValue> {
+        Self::set_callback(&mut self.program_change, ctx, "on_program_change")
+    }
+
+    fn set_clock(&mut self, ctx: &mut CallContext) -> Result<KValue> {
+        Self::set_callback(&mut self.clock, ctx, "on_clock")
+    }
+
+    fn set_transport(&mut self, ctx: &mut CallContext) -> Result<KValue> {
+        Self::set_callback(&mut self.transport, ctx, "on_transport")
+    }
+
+    fn set_sysex(&mut self, ctx: &mut CallContext) -> Result<KValue> {
+        Self::set_callback(&mut self.sysex, ctx, "on_sysex")
+    }
+
+    fn set_macro(&mut self, ctx: &mut CallContext) -> Result<KValue> {
+        Self::set_callback(&mut self.macro_change, ctx, "on_macro")
+    }
+
     fn set_pause(&mut self, ctx: &mut CallContext) -> Result<KValue> {
         Self::set_callback(&mut self.pause, ctx, "on_pause")
     }
 
+    fn set_stop(&mut self, ctx: &mut CallContext) -> Result<KValue> {
+        Self::set_callback(&mut self.stop, ctx, "on_stop")
+    }
+
+    fn set_tempo_change(&mut self, ctx: &mut CallContext) -> Result<KValue> {
+        Self::set_callback(&mut self.tempo_change, ctx, "on_tempo_change")
+    }
+
+    fn set_jump(&mut self, ctx: &mut CallContext) -> Result<KValue> {
+        Self::set_callback(&mut self.jump, ctx, "on_jump")
+    }
+
     fn set_play(&mut self, ctx: &mut CallContext) -> Result<KValue> {
         Self::set_callback(&mut self.play, ctx, "on_play")
     }
 
+    fn set_bar(&mut self, ctx: &mut CallContext) -> Result<KValue> {
+        Self::set_callback(&mut self.bar, ctx, "on_bar")
+    }
+
+    fn set_beat(&mut self, ctx: &mut CallContext) -> Result<KValue> {
+        Self::set_callback(&mut self.beat, ctx, "on_beat")
+    }
+
     fn set_callback(ptr: &mut Option<KValue>, ctx: &mut CallContext, name: &str) -> Result<KValue> {
         use KValue::*;
         match ctx.args() {
@@ -95,26 +289,751 @@ fn print_scales(ctx: &mut CallContext, pipe_in: PipeIn) -> Result<KValue> {
     }
 }
 
-fn midiout(ctx: &mut CallContext, orchestrator: Arc<Mutex<Orchestrator>>) -> Result<KValue> {
-    use KValue::{List, Map, Null, Number};
+fn set_tempo(ctx: &mut CallContext, clock: &Clock) -> Result<KValue> {
+    use KValue::{Null, Number};
+
+    match ctx.args() {
+        [Number(bpm)] if f64::from(bpm) > 0.0 => {
+            clock.set_tempo(f64::from(bpm) as f32);
+            Ok(Null)
+        }
+        _ => runtime_error!(
+            "kotoist.set_tempo: Expected arguments: tempo in BPM (a positive number)."
+        ),
+    }
+}
+
+fn start(ctx: &mut CallContext, clock: &Clock) -> Result<KValue> {
+    use KValue::Null;
+
+    match ctx.args() {
+        [] => {
+            clock.start();
+            Ok(Null)
+        }
+        _ => runtime_error!("kotoist.start: doesn't expect any arguments"),
+    }
+}
+
+fn stop(ctx: &mut CallContext, clock: &Clock) -> Result<KValue> {
+    use KValue::Null;
+
+    match ctx.args() {
+        [] => {
+            clock.stop();
+            Ok(Null)
+        }
+        _ => runtime_error!("kotoist.stop: doesn't expect any arguments"),
+    }
+}
+
+fn note_on(ctx: &mut CallContext, midi_in: &MidiIn) -> Result<KValue> {
+    use KValue::{Null, Number};
+
+    let (note, velocity, channel) = match ctx.args() {
+        [Number(nn), Number(vel)] => (nn, vel, None),
+        [Number(nn), Number(vel), Number(ch)] => (nn, vel, Some(ch)),
+        _ => {
+            return runtime_error!(
+                "kotoist.note_on: Expected arguments: note, velocity, channel (optional)."
+            )
+        }
+    };
+
+    send_midi(
+        midi_in,
+        MidiMessage::NoteOn {
+            note: midi_data_byte(note, "note_on")?,
+            velocity: unit_value(velocity),
+            channel: midi_channel(channel, "note_on")?,
+        },
+        "note_on",
+    )?;
+
+    Ok(Null)
+}
+
+fn note_off(ctx: &mut CallContext, midi_in: &MidiIn) -> Result<KValue> {
+    use KValue::{Null, Number};
+
+    let (note, channel) = match ctx.args() {
+        [Number(nn)] => (nn, None),
+        [Number(nn), Number(ch)] => (nn, Some(ch)),
+        _ => {
+            return runtime_error!(
+                "kotoist.note_off: Expected arguments: note, channel (optional)."
+            )
+        }
+    };
+
+    send_midi(
+        midi_in,
+        MidiMessage::NoteOff {
+            note: midi_data_byte(note, "note_off")?,
+            channel: midi_channel(channel, "note_off")?,
+        },
+        "note_off",
+    )?;
+
+    Ok(Null)
+}
+
+fn note(ctx: &mut CallContext, midi_in: &MidiIn) -> Result<KValue> {
+    use KValue::{Null, Number};
+
+    match ctx.args() {
+        [Number(nn), Number(vel), Number(ch), Number(dur)] if f64::from(dur) >= 0.0 => {
+            send_midi(
+                midi_in,
+                MidiMessage::Note {
+                    note: midi_data_byte(nn, "note")?,
+                    velocity: unit_value(vel),
+                    channel: midi_channel(Some(ch), "note")?,
+                    dur: f64::from(dur),
+                },
+                "note",
+            )?;
+
+            Ok(Null)
+        }
+        _ => runtime_error!(
+            "kotoist.note: Expected arguments: note, velocity, channel, duration in beats."
+        ),
+    }
+}
+
+fn cc(ctx: &mut CallContext, midi_in: &MidiIn) -> Result<KValue> {
+    use KValue::{Null, Number};
+
+    let (cc, value, channel) = match ctx.args() {
+        [Number(cc), Number(value)] => (cc, value, None),
+        [Number(cc), Number(value), Number(ch)] => (cc, value, Some(ch)),
+        _ => {
+            return runtime_error!(
+                "kotoist.cc: Expected arguments: controller number, value, channel (optional)."
+            )
+        }
+    };
+
+    send_midi(
+        midi_in,
+        MidiMessage::Cc {
+            cc: midi_data_byte(cc, "cc")?,
+            value: unit_value(value),
+            channel: midi_channel(channel, "cc")?,
+        },
+        "cc",
+    )?;
+
+    Ok(Null)
+}
+
+fn sysex(ctx: &mut CallContext, midi_in: &MidiIn) -> Result<KValue> {
+    use KValue::{List, Null, Number};
+
     match ctx.args() {
-        [Map(map), Number(quant)] => {
-            let quant = f64::from(quant);
+        [List(bytes)] => {
+            let bytes = bytes
+                .data()
+                .iter()
+                .map(|byte| match byte {
+                    Number(byte) if (0.0..=255.0).contains(&f64::from(byte)) => {
+                        Ok(f64::from(byte) as u8)
+                    }
+                    _ => runtime_error!("kotoist.sysex: the bytes should be numbers from 0 to 255"),
+                })
+                .collect::<Result<Vec<u8>>>()?;
+
+            match SysEx::new(bytes) {
+                Ok(message) => {
+                    send_midi(midi_in, MidiMessage::SysEx(message), "sysex")?;
+                    Ok(Null)
+                }
+                Err(e) => runtime_error!("kotoist.sysex: {}", e),
+            }
+        }
+        _ => runtime_error!("kotoist.sysex: Expected arguments: list of bytes."),
+    }
+}
+
+fn send_midi(midi_in: &MidiIn, message: MidiMessage, fn_name: &str) -> Result<()> {
+    if midi_in.send(message) {
+        Ok(())
+    } else {
+        runtime_error!("kotoist.{}: the MIDI queue is full", fn_name)
+    }
+}
+
+fn midithru(ctx: &mut CallContext, midi_thru: &MidiThru) -> Result<KValue> {
+    use KValue::{Bool, List, Null, Number};
+
+    match ctx.args() {
+        [Bool(enabled)] => {
+            midi_thru.set_channels(None);
+            midi_thru.set_enabled(*enabled);
+            Ok(Null)
+        }
+        [Bool(enabled), List(channels)] => {
+            let channels = channels
+                .data()
+                .iter()
+                .map(|channel| match channel {
+                    Number(channel) => midi_channel(Some(channel), "midithru"),
+                    _ => runtime_error!("kotoist.midithru: channels should be numbers"),
+                })
+                .collect::<Result<Vec<u8>>>()?;
+            midi_thru.set_channels(Some(&channels));
+            midi_thru.set_enabled(*enabled);
+            Ok(Null)
+        }
+        _ => runtime_error!(
+            "kotoist.midithru: Expected arguments: enabled, list of channels (optional)."
+        ),
+    }
+}
+
+fn held_notes(ctx: &mut CallContext, midi_state: &Arc<MidiState>) -> Result<KValue> {
+    match ctx.args() {
+        [] => {
+            let iterator = HeldNotesIterator {
+                midi_state: midi_state.clone(),
+                last: None,
+                rest: "rest".into(),
+            };
+
+            Ok(KIterator::new(iterator).into())
+        }
+        _ => runtime_error!("kotoist.held_notes: doesn't expect any arguments"),
+    }
+}
+
+/// Yields the held notes in ascending order, reading the input state on each pull. It starts
+/// again from the lowest note after the highest one, and yields rests while no notes are held.
+#[derive(Clone)]
+struct HeldNotesIterator {
+    midi_state: Arc<MidiState>,
+    last: Option<u8>,
+    // kept to yield the rests without allocating
+    rest: KString,
+}
+
+impl KotoIterator for HeldNotesIterator {
+    fn make_copy(&self) -> Result<KIterator> {
+        Ok(KIterator::new(self.clone()))
+    }
+}
+
+impl Iterator for HeldNotesIterator {
+    type Item = KIteratorOutput;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let held_notes = &self.midi_state.held_notes;
+        self.last = held_notes
+            .next_above(self.last)
+            .or_else(|| held_notes.next_above(None));
+        match self.last {
+            Some(note) => Some(KIteratorOutput::Value(note.into())),
+            None => Some(KIteratorOutput::Value(KValue::Str(self.rest.clone()))),
+        }
+    }
+}
+
+fn ccin(ctx: &mut CallContext, midi_state: &Arc<MidiState>) -> Result<KValue> {
+    use KValue::Number;
+
+    let (cc, channel) = match ctx.args() {
+        [Number(cc)] => (cc, None),
+        [Number(cc), Number(ch)] => (cc, Some(ch)),
+        _ => {
+            return runtime_error!(
+                "kotoist.ccin: Expected arguments: controller number, channel (optional)."
+            )
+        }
+    };
+
+    let iterator = CcIterator {
+        midi_state: midi_state.clone(),
+        cc: midi_data_byte(cc, "ccin")?,
+        channel: midi_channel(channel, "ccin")?,
+    };
+
+    Ok(KIterator::new(iterator).into())
+}
+
+/// The endless iterator, which yields the latest value of the incoming CC each time it's pulled.
+#[derive(Clone)]
+struct CcIterator {
+    midi_state: Arc<MidiState>,
+    cc: u8,
+    channel: u8,
+}
+
+impl KotoIterator for CcIterator {
+    fn make_copy(&self) -> Result<KIterator> {
+        Ok(KIterator::new(self.clone()))
+    }
+}
+
+impl Iterator for CcIterator {
+    type Item = KIteratorOutput;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let value = self.midi_state.cc.get(self.cc, self.channel);
+        Some(KIteratorOutput::Value(value.into()))
+    }
+}
+
+fn macro_iter(ctx: &mut CallContext, macro_values: &Arc<MacroValues>) -> Result<KValue> {
+    match ctx.args() {
+        [KValue::Number(number)] => {
+            let iterator = MacroIterator {
+                macro_values: macro_values.clone(),
+                index: macro_index(number, "macro")?,
+            };
+
+            Ok(KIterator::new(iterator).into())
+        }
+        _ => runtime_error!("kotoist.macro: Expected arguments: macro number (from 1)."),
+    }
+}
+
+fn macro_value(ctx: &mut CallContext, macro_values: &MacroValues) -> Result<KValue> {
+    match ctx.args() {
+        [KValue::Number(number)] => {
+            let index = macro_index(number, "macro_value")?;
+            Ok(macro_values.get(index).unwrap_or_default().into())
+        }
+        _ => runtime_error!("kotoist.macro_value: Expected arguments: macro number (from 1)."),
+    }
+}
+
+// the macros are numbered from 1 in Koto, as in the host
+fn macro_index(number: &KNumber, fn_name: &str) -> Result<usize> {
+    let number = f64::from(number);
+    if (1.0..=NUM_MACROS as f64).contains(&number) {
+        Ok(number as usize - 1)
+    } else {
+        runtime_error!(
+            "kotoist.{}: macro number {} is out of the range 1..{}",
+            fn_name,
+            number,
+            NUM_MACROS
+        )
+    }
+}
+
+/// The endless iterator, which yields the current value of the macro each time it's pulled.
+#[derive(Clone)]
+struct MacroIterator {
+    macro_values: Arc<MacroValues>,
+    index: usize,
+}
+
+impl KotoIterator for MacroIterator {
+    fn make_copy(&self) -> Result<KIterator> {
+        Ok(KIterator::new(self.clone()))
+    }
+}
+
+impl Iterator for MacroIterator {
+    type Item = KIteratorOutput;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let value = self.macro_values.get(self.index).unwrap_or_default();
+        Some(KIteratorOutput::Value(value.into()))
+    }
+}
+
+fn capture_start(ctx: &mut CallContext, capture: &Mutex<Capture>) -> Result<KValue> {
+    match ctx.args() {
+        [] => {
+            capture.lock().unwrap().start();
+            Ok(KValue::Null)
+        }
+        _ => runtime_error!("kotoist.capture_start: doesn't expect any arguments"),
+    }
+}
+
+fn capture_stop(ctx: &mut CallContext, capture: &Mutex<Capture>) -> Result<KValue> {
+    use KValue::{Null, Number};
+
+    let quantization = match ctx.args() {
+        [] | [Null] => None,
+        [Number(quant)] if f64::from(quant) >= 0.0 => Some(f64::from(quant)),
+        _ => {
+            return runtime_error!(
+                "kotoist.capture_stop: Expected arguments: quantization in beats (optional)."
+            )
+        }
+    };
+
+    let phrase = capture.lock().unwrap().stop(quantization);
+
+    Ok(phrase_to_koto(phrase).into())
+}
+
+fn bind_snippet(ctx: &mut CallContext, snippets: &RwLock<Vec<Snippet>>) -> Result<KValue> {
+    use KValue::{Map, Null, Number};
+
+    let (index, binding) = match ctx.args() {
+        [Number(index), Null] if f64::from(index) >= 0.0 => (f64::from(index) as usize, None),
+        [Number(index), Map(map)] if f64::from(index) >= 0.0 => (
+            f64::from(index) as usize,
+            Some(scene_binding_from_koto(map)?),
+        ),
+        _ => {
+            return runtime_error!(
+                "kotoist.bind_snippet: Expected arguments: snippet index, \
+                {program|note|scene: number, quant} or null."
+            )
+        }
+    };
+
+    match snippets.write().unwrap().get_mut(index) {
+        Some(snippet) => {
+            snippet.binding = binding;
+            Ok(Null)
+        }
+        None => runtime_error!("kotoist.bind_snippet: there's no snippet {}", index),
+    }
+}
+
+fn scene_binding_from_koto(map: &KMap) -> Result<SceneBinding> {
+    let number = |key: &str| match map.get(key) {
+        Some(KValue::Number(value)) => Ok(Some(value)),
+        Some(KValue::Null) | None => Ok(None),
+        Some(other) => runtime_error!(
+            "kotoist.bind_snippet: expected a number for '{}', found '{}'",
+            key,
+            other.type_as_string()
+        ),
+    };
+
+    let trigger = match (number("program")?, number("note")?, number("scene")?) {
+        (Some(program), None, None) => {
+            SceneTrigger::Program(midi_data_byte(&program, "bind_snippet")?)
+        }
+        (None, Some(note), None) => SceneTrigger::Note(midi_data_byte(&note, "bind_snippet")?),
+        (None, None, Some(scene)) => SceneTrigger::Scene(f64::from(scene) as i32),
+        _ => {
+            return runtime_error!(
+                "kotoist.bind_snippet: expected exactly one of 'program', 'note' or 'scene'"
+            )
+        }
+    };
+    // the scenes switch on the next bar by default
+    let quantization = match map.get("quant") {
+        Some(KValue::Null) | None => Quantization::bars(1.0),
+        Some(quant) => match Quantization::try_from(&quant) {
+            Ok(quant) => quant,
+            Err(e) => return runtime_error!("kotoist.bind_snippet: {}", e),
+        },
+    };
+
+    Ok(SceneBinding {
+        trigger,
+        quantization,
+    })
+}
+
+fn at(ctx: &mut CallContext, scheduler: &Mutex<Scheduler>) -> Result<KValue> {
+    match ctx.args() {
+        [KValue::Number(beat), function] if function.is_callable() => {
+            let id = scheduler
+                .lock()
+                .unwrap()
+                .at(f64::from(beat), function.clone());
+            Ok(id.into())
+        }
+        _ => runtime_error!("kotoist.at: Expected arguments: beat position, function."),
+    }
+}
+
+// The interval is counted from the current beat of the transport, not from the last clock tick,
+// which is behind it (or missing at all, if the transport hasn't played yet).
+fn sched(
+    ctx: &mut CallContext,
+    scheduler: &Mutex<Scheduler>,
+    cursor: &AtomicF32,
+) -> Result<KValue> {
+    match ctx.args() {
+        [KValue::Number(interval), function]
+            if f64::from(interval) > 0.0 && function.is_callable() =>
+        {
+            let position = f64::from(cursor.load(Ordering::Relaxed));
+            let id =
+                scheduler
+                    .lock()
+                    .unwrap()
+                    .sched(position, f64::from(interval), function.clone());
+            Ok(id.into())
+        }
+        _ => runtime_error!(
+            "kotoist.sched: Expected arguments: interval in beats (greater than 0), function."
+        ),
+    }
+}
+
+// The routine is either a generator function or an iterator made by it.
+fn routine(
+    ctx: &mut CallContext,
+    scheduler: &Mutex<Scheduler>,
+    cursor: &AtomicF32,
+) -> Result<KValue> {
+    let iterator = match ctx.args() {
+        [KValue::Iterator(iterator)] => iterator.clone(),
+        [function] if function.is_callable() => {
+            let function = function.clone();
+            match ctx.vm.call_function(function, &[])? {
+                KValue::Iterator(iterator) => iterator,
+                other => {
+                    return runtime_error!(
+                        "kotoist.routine: expected a generator, the function returned '{}'",
+                        other.type_as_string()
+                    )
+                }
+            }
+        }
+        _ => return runtime_error!("kotoist.routine: Expected arguments: generator function."),
+    };
+
+    let position = f64::from(cursor.load(Ordering::Relaxed));
+    Ok(scheduler.lock().unwrap().routine(position, iterator).into())
+}
+
+fn cancel(ctx: &mut CallContext, scheduler: &Mutex<Scheduler>) -> Result<KValue> {
+    match ctx.args() {
+        [KValue::Number(id)] if f64::from(id) >= 0.0 => Ok(scheduler
+            .lock()
+            .unwrap()
+            .cancel(f64::from(id) as u64)
+            .into()),
+        _ => runtime_error!(
+            "kotoist.cancel: Expected arguments: the handle returned by at, sched or routine."
+        ),
+    }
+}
+
+fn now(ctx: &mut CallContext, cursor: &AtomicF32) -> Result<KValue> {
+    match ctx.args() {
+        [] => Ok(f64::from(cursor.load(Ordering::Relaxed)).into()),
+        _ => runtime_error!("kotoist.now: doesn't expect any arguments"),
+    }
+}
+
+fn tempo(ctx: &mut CallContext, playback: &PlaybackStatus) -> Result<KValue> {
+    match ctx.args() {
+        [] => Ok(f64::from(playback.tempo()).into()),
+        _ => runtime_error!("kotoist.tempo: doesn't expect any arguments"),
+    }
+}
+
+fn is_playing(ctx: &mut CallContext, playback: &PlaybackStatus) -> Result<KValue> {
+    match ctx.args() {
+        [] => Ok(playback.is_playing().into()),
+        _ => runtime_error!("kotoist.is_playing: doesn't expect any arguments"),
+    }
+}
+
+fn time_signature(ctx: &mut CallContext, playback: &PlaybackStatus) -> Result<KValue> {
+    match ctx.args() {
+        [] => {
+            let (numerator, denominator) = playback.time_signature();
+            Ok(KTuple::from(vec![numerator.into(), denominator.into()]).into())
+        }
+        _ => runtime_error!("kotoist.time_signature: doesn't expect any arguments"),
+    }
+}
+
+fn players(ctx: &mut CallContext, playback: &PlaybackStatus) -> Result<KValue> {
+    match ctx.args() {
+        [] => {
+            let players: Vec<KValue> = playback
+                .players()
+                .into_iter()
+                .map(|player| player_status_to_koto(player).into())
+                .collect();
+            Ok(KList::from_slice(&players).into())
+        }
+        _ => runtime_error!("kotoist.players: doesn't expect any arguments"),
+    }
+}
+
+// The quantization is in the form accepted by `midiout`.
+fn player_status_to_koto(player: PlayerStatus) -> KMap {
+    let quant = player.quantization;
+    let value: KValue = match quant.unit {
+        QuantizationUnit::Beats => quant.value.into(),
+        QuantizationUnit::Bars => format!("{}bars", quant.value).into(),
+    };
+
+    let result = KMap::new();
+    result.insert("name", player.name.as_ref());
+    result.insert(
+        "quant",
+        KList::from_slice(&[value, quant.phase.into(), quant.offset.into()]),
+    );
+    result.insert("is_playing", player.is_playing());
+    result.insert("pending_notes", player.pending_notes());
+
+    result
+}
+
+// The map can be passed to `capture_pattern` to play the phrase. The notes are the degrees of the
+// chromatic scale with the root and octave at 0.
+// The sequences of the phrase are `pseq` patterns, so it can be played as it is.
+fn phrase_to_koto(phrase: Phrase) -> KMap {
+    fn list(values: impl Iterator<Item = KValue>) -> KList {
+        KList::from_slice(&values.collect::<Vec<KValue>>())
+    }
+
+    let result = KMap::new();
+    result.insert(
+        "degree",
+        patterns::seq(list(phrase.degree.into_iter().map(
+            |notes| match notes.as_slice() {
+                [] => "rest".into(),
+                [note] => (*note).into(),
+                notes => list(notes.iter().map(|note| (*note).into())).into(),
+            },
+        ))),
+    );
+    let seq = |values: Vec<f64>| patterns::seq(list(values.into_iter().map(KValue::from)));
+    result.insert("dur", seq(phrase.dur));
+    result.insert("length", seq(phrase.length));
+    result.insert("amp", seq(phrase.amp));
+    result.insert("octave", 0);
+    result.insert("root", 0);
+
+    result
+}
+
+// note or controller number
+fn midi_data_byte(value: &KNumber, fn_name: &str) -> Result<u8> {
+    let value = f64::from(value);
+    if (0.0..=127.0).contains(&value) {
+        Ok(value as u8)
+    } else {
+        runtime_error!("kotoist.{}: {} is out of the range 0..127", fn_name, value)
+    }
+}
+
+fn midi_channel(value: Option<&KNumber>, fn_name: &str) -> Result<u8> {
+    let value = value.map(f64::from).unwrap_or_default();
+    if (0.0..=15.0).contains(&value) {
+        Ok(value as u8)
+    } else {
+        runtime_error!(
+            "kotoist.{}: channel {} is out of the range 0..15",
+            fn_name,
+            value
+        )
+    }
+}
+
+// velocity or CC value
+fn unit_value(value: &KNumber) -> f32 {
+    f64::from(value).clamp(0.0, 1.0) as f32
+}
+
+// The hook is called by the interpreter for the events prefetched by the orchestrator.
+fn on_event(
+    ctx: &mut CallContext,
+    callbacks: &Mutex<Callbacks>,
+    orchestrator: &Mutex<Orchestrator>,
+) -> Result<KValue> {
+    let hook = match ctx.args() {
+        [KValue::Null] => None,
+        [function] if function.is_callable() => Some(EventHook::new(function.clone())),
+        _ => return runtime_error!("kotoist.on_event: Expected arguments: function or null."),
+    };
+
+    orchestrator
+        .lock()
+        .unwrap()
+        .set_event_hooked(hook.is_some());
+    callbacks.lock().unwrap().event = hook;
+
+    Ok(KValue::Null)
+}
+
+fn tempo_ratio(ctx: &mut CallContext, orchestrator: &Mutex<Orchestrator>) -> Result<KValue> {
+    use KValue::{Null, Number};
+
+    match ctx.args() {
+        [player, Number(ratio)] if f64::from(ratio) > 0.0 => {
+            let player = player_id_from_koto(player)?;
+
+            if orchestrator
+                .lock()
+                .unwrap()
+                .set_tempo_ratio(&player, f64::from(ratio))
+            {
+                Ok(Null)
+            } else {
+                runtime_error!("kotoist.tempo_ratio: no player {:?}", player)
+            }
+        }
+        _ => runtime_error!(
+            "kotoist.tempo_ratio: \
+                Expected arguments: player index or name, positive ratio."
+        ),
+    }
+}
+
+fn tempo_curve(ctx: &mut CallContext, orchestrator: &Mutex<Orchestrator>) -> Result<KValue> {
+    use KValue::Null;
+
+    match ctx.args() {
+        [player, curve] => {
+            let player = player_id_from_koto(player)?;
+            let curve = match curve {
+                Null => None,
+                curve => match TempoCurve::try_from(curve) {
+                    Ok(curve) => Some(curve),
+                    Err(e) => return runtime_error!("kotoist.tempo_curve: {}", e),
+                },
+            };
+
+            if orchestrator.lock().unwrap().set_tempo_curve(&player, curve) {
+                Ok(Null)
+            } else {
+                runtime_error!("kotoist.tempo_curve: no player {:?}", player)
+            }
+        }
+        _ => runtime_error!(
+            "kotoist.tempo_curve: \
+                Expected arguments: player index or name, tempo curve or null."
+        ),
+    }
+}
+
+// The players are published to the status, as only the interpreter changes them.
+fn midiout(
+    ctx: &mut CallContext,
+    orchestrator: Arc<Mutex<Orchestrator>>,
+    seed: &RwLock<u64>,
+    playback: &PlaybackStatus,
+) -> Result<KValue> {
+    use KValue::{List, Map, Null};
+    let seed = *seed.read().unwrap();
+    match ctx.args() {
+        [Map(map), quant] => {
+            let quant = quantization_from_koto(quant)?;
 
             match Pattern::try_from(map) {
                 Ok(pattern) => {
-                    orchestrator
-                        .lock()
-                        .unwrap()
-                        .set_patterns(vec![pattern], quant);
+                    let mut orchestrator = orchestrator.lock().unwrap();
+                    orchestrator.set_patterns(vec![pattern], quant, seed);
+                    playback.set_players(orchestrator.players_status());
                 }
                 Err(e) => return runtime_error!("{}", e),
             }
 
             Ok(Null)
         }
-        [List(list), Number(quant)] => {
-            let quant = f64::from(quant);
+        [List(list), quant] => {
+            let quant = quantization_from_koto(quant)?;
             let mut patterns = Vec::new();
 
             for item in list.clone().data().iter() {
@@ -134,7 +1053,9 @@ fn midiout(ctx: &mut CallContext, orchestrator: Arc<Mutex<Orchestrator>>) -> Res
                 }
             }
 
-            orchestrator.lock().unwrap().set_patterns(patterns, quant);
+            let mut orchestrator = orchestrator.lock().unwrap();
+            orchestrator.set_patterns(patterns, quant, seed);
+            playback.set_players(orchestrator.players_status());
 
             Ok(Null)
         }
@@ -144,3 +1065,189 @@ fn midiout(ctx: &mut CallContext, orchestrator: Arc<Mutex<Orchestrator>>) -> Res
         ),
     }
 }
+
+// The seed applies to the patterns passed to `midiout` afterwards.
+fn set_seed(ctx: &mut CallContext, seed: &RwLock<u64>) -> Result<KValue> {
+    match ctx.args() {
+        [] => Ok((*seed.read().unwrap() as i64).into()),
+        [KValue::Number(n)] => {
+            let n = f64::from(n) as i64 as u64;
+            *seed.write().unwrap() = n;
+            // the numbers drawn outside of the players follow the seed too
+            random_module::seed(ctx.vm, n)?;
+            Ok(KValue::Null)
+        }
+        _ => runtime_error!("kotoist.seed: Expected arguments: number or nothing."),
+    }
+}
+
+fn quantization_from_koto(value: &KValue) -> Result<Quantization> {
+    match Quantization::try_from(value) {
+        Ok(quant) => Ok(quant),
+        Err(e) => runtime_error!("kotoist.midiout: {}", e),
+    }
+}
+
+fn player_id_from_koto(value: &KValue) -> Result<PlayerId> {
+    match value {
+        KValue::Number(index) if f64::from(index) >= 0.0 => {
+            Ok(PlayerId::Index(f64::from(index) as usize))
+        }
+        KValue::Str(name) => Ok(PlayerId::Name(name.as_str().to_string())),
+        other => runtime_error!(
+            "Unexpected player '{}', expected an index or a name",
+            other.type_as_string()
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::interpreter::interpreter::KOTO_LIB_CODE;
+    use crate::interpreter::random_module::make_module as make_random_module;
+    use crate::midi::{new_midi_queue, HeldNotes};
+    use crate::orchestrator::EventValue;
+    use crate::pipe::new_pipe;
+
+    #[test]
+    fn test_held_notes_iterator() {
+        let midi_state = Arc::new(MidiState::default());
+        let mut iterator = HeldNotesIterator {
+            midi_state: midi_state.clone(),
+            last: None,
+            rest: "rest".into(),
+        };
+        let mut pull = || match iterator.next() {
+            Some(KIteratorOutput::Value(KValue::Number(note))) => Some(i64::from(note)),
+            Some(KIteratorOutput::Value(KValue::Str(rest))) if rest.as_str() == "rest" => None,
+            _ => panic!("the held notes should never end"),
+        };
+
+        // nothing is held yet
+        assert_eq!(pull(), None);
+
+        for note in [64, 60, 67] {
+            midi_state.held_notes.press(note);
+        }
+        let notes: Vec<_> = (0..7).map(|_| pull()).collect();
+        assert_eq!(
+            notes,
+            [
+                Some(60),
+                Some(64),
+                Some(67),
+                Some(60),
+                Some(64),
+                Some(67),
+                Some(60)
+            ]
+        );
+
+        // the keys are let go, and the arpeggio goes on with the new ones
+        for note in [64, 60, 67] {
+            midi_state.held_notes.release(note);
+        }
+        assert_eq!(pull(), None);
+        assert_eq!(pull(), None);
+        midi_state.held_notes.press(62);
+        assert_eq!(pull(), Some(62));
+        assert_eq!(pull(), Some(62));
+    }
+
+    #[test]
+    fn test_markov_from_midi() {
+        let (pipe_in, _pipe_out) = new_pipe();
+        let midi_state = Arc::new(MidiState::default());
+        let capture = Arc::new(Mutex::new(Capture::default()));
+        let module = make_module(ModuleContext {
+            orchestrator: Arc::new(Mutex::new(Orchestrator::new(
+                pipe_in.clone(),
+                midi_state.clone(),
+            ))),
+            snippets: Default::default(),
+            callbacks: Default::default(),
+            pipe_in,
+            clock: Default::default(),
+            midi_in: new_midi_queue().0,
+            midi_state,
+            capture: capture.clone(),
+            macro_values: Default::default(),
+            scheduler: Default::default(),
+            cursor: Arc::new(AtomicF32::new(0.0)),
+            playback: Default::default(),
+            seed: Default::default(),
+        });
+        let mut koto = Koto::default();
+        koto.prelude().insert("kotoist", module);
+        koto.prelude().insert("random", make_random_module());
+        koto.compile_and_run(KOTO_LIB_CODE)
+            .expect("the pattern library should run");
+
+        // each note has its own duration, length and amplitude
+        {
+            let mut capture = capture.lock().unwrap();
+            capture.start();
+            for (note, velocity, start, end) in [
+                (60, 1.0, 0.0, 0.5),
+                (62, 0.5, 1.0, 1.25),
+                (64, 0.25, 1.5, 3.0),
+                (60, 1.0, 3.0, 3.5),
+            ] {
+                capture.record(note, velocity, start);
+                capture.record(note, 0.0, end);
+            }
+        }
+
+        koto.compile_and_run(
+            "
+keys = ['degree', 'dur', 'length', 'amp']
+phrase = kotoist.capture_stop()
+columns = keys.each(|key| phrase.get(key).copy().to_list()).to_list()
+expected = (0..columns[0].size()).each(|i| columns.each(|column| column[i]).to_tuple()).to_list()
+
+pattern = markov_from_midi phrase, 1, 4
+count = 0
+loop
+  step = keys.each(|key| pattern.get(key).try_next()).to_tuple()
+  if step[0] == null then break
+  # the steps are learned as a whole, so they're never mixed
+  assert expected.contains step
+  count += 1
+assert_eq count, expected.size() * 4
+",
+        )
+        .expect("the steps of the phrase should be kept");
+    }
+
+    #[test]
+    fn test_phrase_to_koto() {
+        let phrase = Phrase {
+            degree: vec![vec![], vec![60, 64], vec![67]],
+            dur: vec![0.5, 1.0, 0.5],
+            length: vec![1.0, 0.5, 1.0],
+            amp: vec![0.0, 1.0, 0.5],
+        };
+
+        // the phrase is a pattern, which `midiout` plays once
+        let mut pattern = Pattern::try_from(&phrase_to_koto(phrase))
+            .expect("the phrase should be a valid pattern");
+        let held_notes = HeldNotes::default();
+        let mut events = Vec::new();
+        while let Some(event) = pattern.try_next(0, &held_notes).unwrap() {
+            events.push((event.value, event.dur, event.length));
+        }
+        assert_eq!(
+            events,
+            vec![
+                (vec![EventValue::Rest], 0.5, 1.0),
+                (
+                    vec![EventValue::Note(60, 127, 0), EventValue::Note(64, 127, 0)],
+                    1.0,
+                    0.5
+                ),
+                (vec![EventValue::Note(67, 63, 0)], 0.5, 1.0),
+            ]
+        );
+    }
+}
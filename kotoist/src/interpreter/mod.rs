@@ -1,5 +1,9 @@
 pub(crate) use self::interpreter::*;
 
+mod capture;
 mod interpreter;
 /// Native functions exposed to koto.
 mod kotoist_module;
+mod patterns;
+mod random_module;
+mod scheduler;
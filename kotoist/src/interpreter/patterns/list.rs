@@ -0,0 +1,1138 @@
+//! The patterns, which embed the values of lists.
+use std::sync::Arc;
+
+use koto::{prelude::*, runtime::Result};
+
+use super::{
+    arg, copy_value, is_truthy, iterator, knumber, list, make_expanding, make_pattern, next_value,
+    number, or, wrapped_item, Generator, Items, Random, Source,
+};
+
+pub(super) fn pseq(ctx: &mut CallContext) -> Result<KValue> {
+    let args = ctx.args();
+    Ok(make_expanding(Seq {
+        list: list(&arg(args, 0), "pseq")?,
+        repeats: number(&or(arg(args, 1), 1), "pseq")?,
+        offset: Source::or(arg(args, 2), 0),
+        current: None,
+    }))
+}
+
+/// The `pseq` pattern, which yields the items of the list once.
+pub(super) fn seq(list: KList) -> KValue {
+    make_expanding(Seq {
+        list,
+        repeats: 1.0,
+        offset: Source::or(KValue::Null, 0),
+        current: None,
+    })
+}
+
+struct Seq {
+    list: KList,
+    repeats: f64,
+    offset: Source,
+    // the offset and the position in the current repeat
+    current: Option<(f64, usize)>,
+}
+
+impl Items for Seq {
+    fn next_item(&mut self) -> Result<Option<KValue>> {
+        let size = self.list.size();
+        if size == 0 {
+            return Ok(None);
+        }
+
+        loop {
+            if let Some((offset, n)) = self.current.as_mut() {
+                if *n < size {
+                    let item = wrapped_item(&self.list, *n as f64 + *offset);
+                    *n += 1;
+                    return Ok(Some(item));
+                }
+                self.current = None;
+                self.repeats -= 1.0;
+            }
+
+            if self.repeats <= 0.0 {
+                return Ok(None);
+            }
+            match self.offset.next_number("pseq")? {
+                Some(offset) => self.current = Some((offset, 0)),
+                None => return Ok(None),
+            }
+        }
+    }
+
+    fn copy(&self) -> Result<Self> {
+        Ok(Self {
+            list: self.list.clone(),
+            repeats: self.repeats,
+            offset: self.offset.copy()?,
+            current: self.current,
+        })
+    }
+}
+
+pub(super) fn pser(ctx: &mut CallContext) -> Result<KValue> {
+    let args = ctx.args();
+    Ok(make_expanding(Ser {
+        list: list(&arg(args, 0), "pser")?,
+        repeats: number(&or(arg(args, 1), 1), "pser")?,
+        offset: Source::or(arg(args, 2), 0),
+        n: 0,
+    }))
+}
+
+struct Ser {
+    list: KList,
+    repeats: f64,
+    offset: Source,
+    // counts the items, as the repeats can be infinite
+    n: usize,
+}
+
+impl Items for Ser {
+    fn next_item(&mut self) -> Result<Option<KValue>> {
+        if self.list.size() == 0 || self.repeats <= 0.0 {
+            return Ok(None);
+        }
+
+        match self.offset.next_number("pser")? {
+            Some(offset) => {
+                let item = wrapped_item(&self.list, offset + self.n as f64);
+                self.n += 1;
+                self.repeats -= 1.0;
+                Ok(Some(item))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn copy(&self) -> Result<Self> {
+        Ok(Self {
+            list: self.list.clone(),
+            repeats: self.repeats,
+            offset: self.offset.copy()?,
+            n: self.n,
+        })
+    }
+}
+
+pub(super) fn prand(ctx: &mut CallContext) -> Result<KValue> {
+    let list = list(&arg(ctx.args(), 0), "prand")?;
+    let repeats = number(&or(arg(ctx.args(), 1), 1), "prand")?;
+
+    Ok(make_expanding(Rand {
+        list: list.into(),
+        repeats,
+        random: Random::new(ctx, "prand")?,
+    }))
+}
+
+struct Rand {
+    list: KValue,
+    repeats: f64,
+    random: Random,
+}
+
+impl Items for Rand {
+    fn next_item(&mut self) -> Result<Option<KValue>> {
+        if self.repeats <= 0.0 {
+            return Ok(None);
+        }
+
+        match self.random.pick(&self.list)? {
+            KValue::Null => Ok(None),
+            item => {
+                self.repeats -= 1.0;
+                Ok(Some(item))
+            }
+        }
+    }
+
+    fn copy(&self) -> Result<Self> {
+        Ok(Self {
+            list: self.list.clone(),
+            repeats: self.repeats,
+            random: self.random.clone(),
+        })
+    }
+}
+
+pub(super) fn pxrand(ctx: &mut CallContext) -> Result<KValue> {
+    let list = list(&arg(ctx.args(), 0), "pxrand")?;
+    let repeats = number(&or(arg(ctx.args(), 1), 1), "pxrand")?;
+
+    Ok(make_expanding(XRand {
+        list,
+        repeats,
+        index: None,
+        random: Random::new(ctx, "pxrand")?,
+    }))
+}
+
+struct XRand {
+    list: KList,
+    repeats: f64,
+    // the index of the previous item
+    index: Option<f64>,
+    random: Random,
+}
+
+impl Items for XRand {
+    fn next_item(&mut self) -> Result<Option<KValue>> {
+        let size = self.list.size() as f64;
+        if size == 0.0 {
+            return Ok(None);
+        }
+
+        let index = match self.index {
+            Some(index) => index,
+            None => (size * self.random.number()?).floor(),
+        };
+        self.index = Some(index);
+        if self.repeats <= 0.0 {
+            return Ok(None);
+        }
+
+        // any item, but the previous one
+        let index = (index + ((size - 1.0) * self.random.number()?).floor() + 1.0) % size;
+        self.index = Some(index);
+        self.repeats -= 1.0;
+
+        Ok(Some(wrapped_item(&self.list, index)))
+    }
+
+    fn copy(&self) -> Result<Self> {
+        Ok(Self {
+            list: self.list.clone(),
+            repeats: self.repeats,
+            index: self.index,
+            random: self.random.clone(),
+        })
+    }
+}
+
+pub(super) fn pwrand(ctx: &mut CallContext) -> Result<KValue> {
+    let list = list(&arg(ctx.args(), 0), "pwrand")?;
+    let weights = Source::new(arg(ctx.args(), 1));
+    let repeats = number(&or(arg(ctx.args(), 2), 1), "pwrand")?;
+
+    Ok(make_expanding(WRand {
+        list,
+        weights,
+        repeats,
+        random: Random::new(ctx, "pwrand")?,
+    }))
+}
+
+struct WRand {
+    list: KList,
+    weights: Source,
+    repeats: f64,
+    random: Random,
+}
+
+impl WRand {
+    /// The equivalent of `list.windex`.
+    fn index(&self, weights: &KValue) -> Result<usize> {
+        let weights = list(weights, "pwrand")?;
+        let r = self.random.number()?;
+        let mut sum = 0.0;
+
+        for (n, weight) in weights.data().iter().enumerate() {
+            sum += number(weight, "pwrand")?;
+            if sum >= r {
+                return Ok(n);
+            }
+        }
+
+        runtime_error!("kotoist.pwrand: the weights should sum up to 1")
+    }
+}
+
+impl Items for WRand {
+    fn next_item(&mut self) -> Result<Option<KValue>> {
+        if self.repeats <= 0.0 {
+            return Ok(None);
+        }
+
+        let weights = match self.weights.try_next()? {
+            KValue::Null => return Ok(None),
+            weights => weights,
+        };
+        let index = self.index(&weights)?;
+        self.repeats -= 1.0;
+
+        match self.list.data().get(index) {
+            Some(item) => Ok(Some(item.clone())),
+            None => {
+                runtime_error!("kotoist.pwrand: there are more weights than the items of the list")
+            }
+        }
+    }
+
+    fn copy(&self) -> Result<Self> {
+        Ok(Self {
+            list: self.list.clone(),
+            weights: self.weights.copy()?,
+            repeats: self.repeats,
+            random: self.random.clone(),
+        })
+    }
+}
+
+pub(super) fn pshuf(ctx: &mut CallContext) -> Result<KValue> {
+    let list = list(&arg(ctx.args(), 0), "pshuf")?;
+    let repeats = number(&or(arg(ctx.args(), 1), 1), "pshuf")?;
+
+    Ok(make_expanding(Shuf {
+        list,
+        shuffled: None,
+        repeats,
+        n: 0,
+        random: Random::new(ctx, "pshuf")?,
+    }))
+}
+
+struct Shuf {
+    list: KList,
+    // it's shuffled once, when the first value is requested
+    shuffled: Option<Vec<KValue>>,
+    repeats: f64,
+    n: usize,
+    random: Random,
+}
+
+impl Shuf {
+    /// The equivalent of `list.scramble`.
+    fn scramble(&self) -> Result<Vec<KValue>> {
+        let mut items = self.list.data().to_vec();
+        let mut result = Vec::with_capacity(items.len());
+
+        while !items.is_empty() {
+            let index = (items.len() as f64 * self.random.number()?).floor() as usize;
+            result.push(items.remove(index.min(items.len() - 1)));
+        }
+
+        Ok(result)
+    }
+}
+
+impl Items for Shuf {
+    fn next_item(&mut self) -> Result<Option<KValue>> {
+        if self.shuffled.is_none() {
+            self.shuffled = Some(self.scramble()?);
+        }
+        let shuffled = self.shuffled.as_ref().unwrap();
+        if shuffled.is_empty() {
+            return Ok(None);
+        }
+
+        loop {
+            if self.repeats <= 0.0 {
+                return Ok(None);
+            }
+            if let Some(item) = shuffled.get(self.n) {
+                self.n += 1;
+                return Ok(Some(item.clone()));
+            }
+            self.n = 0;
+            self.repeats -= 1.0;
+        }
+    }
+
+    fn copy(&self) -> Result<Self> {
+        Ok(Self {
+            list: self.list.clone(),
+            shuffled: self.shuffled.clone(),
+            repeats: self.repeats,
+            n: self.n,
+            random: self.random.clone(),
+        })
+    }
+}
+
+pub(super) fn pindex(ctx: &mut CallContext) -> Result<KValue> {
+    let args = ctx.args();
+    Ok(make_expanding(Index {
+        lists: Source::new(arg(args, 0)),
+        indices: arg(args, 1),
+        repeats: number(&or(arg(args, 2), 1), "pindex")?,
+        current: None,
+    }))
+}
+
+struct Index {
+    lists: Source,
+    indices: KValue,
+    repeats: f64,
+    // the list, the indices into it and the number of the items taken in the current repeat
+    current: Option<(KList, Source, usize)>,
+}
+
+impl Items for Index {
+    fn next_item(&mut self) -> Result<Option<KValue>> {
+        loop {
+            if let Some((list, indices, count)) = self.current.as_mut() {
+                match indices.next_number("pindex")? {
+                    Some(index) => {
+                        if list.size() == 0 {
+                            return runtime_error!("kotoist.pindex: the list is empty");
+                        }
+                        *count += 1;
+                        return Ok(Some(wrapped_item(list, index)));
+                    }
+                    None if *count == 0 => return Ok(None),
+                    None => {
+                        self.current = None;
+                        self.repeats -= 1.0;
+                    }
+                }
+            }
+
+            if self.repeats <= 0.0 {
+                return Ok(None);
+            }
+            match self.lists.try_next()? {
+                KValue::Null => return Ok(None),
+                value => {
+                    // the index pattern continues from where it stopped
+                    let indices = Source::new(self.indices.clone());
+                    self.current = Some((list(&value, "pindex")?, indices, 0));
+                }
+            }
+        }
+    }
+
+    fn copy(&self) -> Result<Self> {
+        let current = match &self.current {
+            Some((list, indices, count)) => Some((list.clone(), indices.copy()?, *count)),
+            None => None,
+        };
+
+        Ok(Self {
+            lists: self.lists.copy()?,
+            indices: copy_value(&self.indices)?,
+            repeats: self.repeats,
+            current,
+        })
+    }
+}
+
+pub(super) fn pfsm(ctx: &mut CallContext) -> Result<KValue> {
+    let list = list(&arg(ctx.args(), 0), "pfsm")?;
+    let repeats = number(&or(arg(ctx.args(), 1), 1), "pfsm")?;
+
+    Ok(make_expanding(Fsm {
+        list,
+        repeats,
+        index: None,
+        random: Random::new(ctx, "pfsm")?,
+    }))
+}
+
+/// The list is the entry states followed by the pairs of an item and its next states. The item
+/// `null` is the final state.
+struct Fsm {
+    list: KList,
+    repeats: f64,
+    // the index of the next states of the current state, `None` at the start of a repeat
+    index: Option<usize>,
+    random: Random,
+}
+
+impl Items for Fsm {
+    fn next_item(&mut self) -> Result<Option<KValue>> {
+        let size = self.list.size();
+        if size == 0 {
+            return Ok(None);
+        }
+        let max_state = ((size as f64 - 1.0) / 2.0).floor() - 1.0;
+
+        loop {
+            let index = match self.index {
+                Some(index) => index,
+                None if self.repeats > 0.0 => 0,
+                None => return Ok(None),
+            };
+
+            let next_states = match self.list.data().get(index) {
+                Some(next_states) => next_states.clone(),
+                None => {
+                    return runtime_error!(
+                        "kotoist.pfsm: the state {} has no next states",
+                        index / 2
+                    )
+                }
+            };
+            let state = match self.random.pick(&next_states)? {
+                KValue::Number(state) => f64::from(state).min(max_state).max(0.0),
+                other => {
+                    return runtime_error!(
+                        "kotoist.pfsm: expected a state number, but found '{}'",
+                        other.type_as_string()
+                    )
+                }
+            };
+            let index = state as usize * 2 + 2;
+
+            match self.list.data().get(index - 1).cloned() {
+                Some(KValue::Null) | None => {
+                    self.index = None;
+                    self.repeats -= 1.0;
+                }
+                Some(item) => {
+                    self.index = Some(index);
+                    return Ok(Some(item));
+                }
+            }
+        }
+    }
+
+    fn copy(&self) -> Result<Self> {
+        Ok(Self {
+            list: self.list.clone(),
+            repeats: self.repeats,
+            index: self.index,
+            random: self.random.clone(),
+        })
+    }
+}
+
+pub(super) fn pmarkov(ctx: &mut CallContext) -> Result<KValue> {
+    let items = list(&arg(ctx.args(), 0), "pmarkov")?.data().to_vec();
+    let order = number(&or(arg(ctx.args(), 1), 1), "pmarkov")?;
+    let repeats = number(&or(arg(ctx.args(), 2), 1), "pmarkov")?;
+    let order = (order.floor().max(1.0) as usize).min(items.len().max(1));
+
+    // the list is wrapped around, so each context has at least one successor
+    let size = items.len();
+    let mut transitions: Vec<(Vec<KValue>, Vec<KValue>)> = Vec::new();
+    for i in 0..size {
+        let context: Vec<KValue> = (0..order).map(|n| items[(i + n) % size].clone()).collect();
+        let successor = items[(i + order) % size].clone();
+        match transitions
+            .iter_mut()
+            .find(|(other, _)| same_items(other, &context))
+        {
+            Some((_, successors)) => successors.push(successor),
+            None => transitions.push((context, vec![successor])),
+        }
+    }
+
+    Ok(make_pattern(Markov {
+        transitions: transitions
+            .into_iter()
+            .map(|(context, successors)| (context, KList::from_slice(&successors).into()))
+            .collect(),
+        context: items.iter().take(order).cloned().collect(),
+        order: order as f64,
+        count: 0.0,
+        total: size as f64 * repeats,
+        random: Random::new(ctx, "pmarkov")?,
+    }))
+}
+
+/// Starts with the first items of the list, then each item is chosen among the ones, which follow
+/// the previous `order` items in the list, as often as they follow them.
+struct Markov {
+    /// The contexts with the lists of their successors.
+    transitions: Arc<[(Vec<KValue>, KValue)]>,
+    context: Vec<KValue>,
+    order: f64,
+    count: f64,
+    total: f64,
+    random: Random,
+}
+
+impl Generator for Markov {
+    fn generate(&mut self) -> Result<Option<KValue>> {
+        if self.count >= self.total {
+            return Ok(None);
+        }
+
+        let item = if self.count < self.order {
+            self.context[self.count as usize].clone()
+        } else {
+            let successors = match self
+                .transitions
+                .iter()
+                .find(|(context, _)| same_items(context, &self.context))
+            {
+                Some((_, successors)) => successors,
+                None => return runtime_error!("kotoist.pmarkov: the context has no successors"),
+            };
+            let item = self.random.pick(successors)?;
+            self.context.remove(0);
+            self.context.push(item.clone());
+            item
+        };
+        self.count += 1.0;
+
+        Ok(Some(item))
+    }
+
+    fn copy(&self) -> Result<Self> {
+        Ok(Self {
+            transitions: self.transitions.clone(),
+            context: self.context.clone(),
+            order: self.order,
+            count: self.count,
+            total: self.total,
+            random: self.random.clone(),
+        })
+    }
+}
+
+fn same_items(a: &[KValue], b: &[KValue]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(a, b)| same(a, b))
+}
+
+/// The equivalent of `==` for the values, which can be learned by `pmarkov`. The other values are
+/// never the same.
+fn same(a: &KValue, b: &KValue) -> bool {
+    match (a, b) {
+        (KValue::Null, KValue::Null) => true,
+        (KValue::Bool(a), KValue::Bool(b)) => a == b,
+        (KValue::Number(a), KValue::Number(b)) => f64::from(a) == f64::from(b),
+        (KValue::Str(a), KValue::Str(b)) => a.as_str() == b.as_str(),
+        (KValue::List(a), KValue::List(b)) => same_items(&a.data(), &b.data()),
+        (KValue::Tuple(a), KValue::Tuple(b)) => same_items(a, b),
+        _ => false,
+    }
+}
+
+pub(super) fn place(ctx: &mut CallContext) -> Result<KValue> {
+    let args = ctx.args();
+    Ok(make_pattern(Lace {
+        list: list(&arg(args, 0), "place")?,
+        repeats: number(&or(arg(args, 1), 1), "place")?,
+        offset: Source::or(arg(args, 2), 0),
+        current: None,
+        repeat_num: 0,
+    }))
+}
+
+/// The sublists and the patterns among the items are taken one value at a time.
+struct Lace {
+    list: KList,
+    repeats: f64,
+    offset: Source,
+    // the offset and the position in the current repeat
+    current: Option<(f64, usize)>,
+    // counts the repeats, as they can be infinite
+    repeat_num: usize,
+}
+
+impl Generator for Lace {
+    fn generate(&mut self) -> Result<Option<KValue>> {
+        let size = self.list.size();
+        if size == 0 {
+            return Ok(None);
+        }
+
+        loop {
+            if let Some((offset, i)) = self.current.as_mut() {
+                if *i < size {
+                    let item = match wrapped_item(&self.list, *i as f64 + *offset) {
+                        KValue::List(sublist) if sublist.size() == 0 => {
+                            return runtime_error!("kotoist.place: the sublist is empty")
+                        }
+                        KValue::List(sublist) => wrapped_item(&sublist, self.repeat_num as f64),
+                        item => item,
+                    };
+                    *i += 1;
+                    return match item {
+                        KValue::Iterator(mut iterator) => next_value(&mut iterator).map(Some),
+                        item => Ok(Some(item)),
+                    };
+                }
+                self.current = None;
+                self.repeat_num += 1;
+                self.repeats -= 1.0;
+            }
+
+            if self.repeats <= 0.0 {
+                return Ok(None);
+            }
+            match self.offset.next_number("place")? {
+                Some(offset) => self.current = Some((offset, 0)),
+                None => return Ok(None),
+            }
+        }
+    }
+
+    fn copy(&self) -> Result<Self> {
+        Ok(Self {
+            list: self.list.clone(),
+            repeats: self.repeats,
+            offset: self.offset.copy()?,
+            current: self.current,
+            repeat_num: self.repeat_num,
+        })
+    }
+}
+
+pub(super) fn pslide(ctx: &mut CallContext) -> Result<KValue> {
+    let args = ctx.args();
+    Ok(make_pattern(Slide {
+        list: list(&arg(args, 0), "pslide")?,
+        repeats: number(&or(arg(args, 1), 1), "pslide")?,
+        lengths: Source::or(arg(args, 2), 3),
+        steps: Source::or(arg(args, 3), 1),
+        position: number(&or(arg(args, 4), 0), "pslide")?,
+        wrap_at_end: is_truthy(&or(arg(args, 5), true)),
+        current: None,
+    }))
+}
+
+/// The patterns among the items are taken one value at a time.
+struct Slide {
+    list: KList,
+    repeats: f64,
+    lengths: Source,
+    steps: Source,
+    position: f64,
+    wrap_at_end: bool,
+    // the length of the current segment and the position in it
+    current: Option<(usize, usize)>,
+}
+
+impl Generator for Slide {
+    fn generate(&mut self) -> Result<Option<KValue>> {
+        let size = self.list.size();
+        if size == 0 {
+            return Ok(None);
+        }
+
+        loop {
+            if let Some((length, n)) = self.current.as_mut() {
+                if *n < *length {
+                    let position = self.position + *n as f64;
+                    *n += 1;
+                    let item = if self.wrap_at_end {
+                        wrapped_item(&self.list, position)
+                    } else {
+                        match self.list.data().get(position as usize) {
+                            Some(item) if position >= 0.0 && !matches!(item, KValue::Null) => {
+                                item.clone()
+                            }
+                            _ => return Ok(None),
+                        }
+                    };
+
+                    match item {
+                        KValue::Iterator(mut iterator) => match next_value(&mut iterator)? {
+                            // the rest of the segment is skipped
+                            KValue::Null => *n = *length,
+                            value => return Ok(Some(value)),
+                        },
+                        item => return Ok(Some(item)),
+                    }
+                    continue;
+                }
+
+                self.current = None;
+                match self.steps.next_number("pslide")? {
+                    Some(step) => self.position += step,
+                    None => return Ok(None),
+                }
+                self.repeats -= 1.0;
+            }
+
+            if self.repeats <= 0.0 {
+                return Ok(None);
+            }
+            match self.lengths.next_number("pslide")? {
+                Some(length) => self.current = Some((length.max(0.0) as usize, 0)),
+                None => return Ok(None),
+            }
+        }
+    }
+
+    fn copy(&self) -> Result<Self> {
+        Ok(Self {
+            list: self.list.clone(),
+            repeats: self.repeats,
+            lengths: self.lengths.copy()?,
+            steps: self.steps.copy()?,
+            position: self.position,
+            wrap_at_end: self.wrap_at_end,
+            current: self.current,
+        })
+    }
+}
+
+pub(super) fn pswitch(ctx: &mut CallContext) -> Result<KValue> {
+    let args = ctx.args();
+    let list = list(&arg(args, 0), "pswitch")?;
+    if list.size() == 0 {
+        return runtime_error!("kotoist.pswitch: the list is empty");
+    }
+
+    Ok(make_expanding(Switch {
+        list,
+        indices: Source::or(arg(args, 1), 0),
+    }))
+}
+
+struct Switch {
+    list: KList,
+    indices: Source,
+}
+
+impl Items for Switch {
+    fn next_item(&mut self) -> Result<Option<KValue>> {
+        Ok(self
+            .indices
+            .next_number("pswitch")?
+            .map(|index| wrapped_item(&self.list, index)))
+    }
+
+    fn copy(&self) -> Result<Self> {
+        Ok(Self {
+            list: self.list.clone(),
+            indices: self.indices.copy()?,
+        })
+    }
+}
+
+pub(super) fn ptuple(ctx: &mut CallContext) -> Result<KValue> {
+    let args = ctx.args();
+    Ok(make_pattern(Tuple {
+        list: list(&arg(args, 0), "ptuple")?,
+        repeats: number(&or(arg(args, 1), 1), "ptuple")?,
+        streams: None,
+    }))
+}
+
+struct Tuple {
+    list: KList,
+    repeats: f64,
+    streams: Option<Vec<Source>>,
+}
+
+impl Generator for Tuple {
+    fn generate(&mut self) -> Result<Option<KValue>> {
+        loop {
+            if let Some(streams) = self.streams.as_mut() {
+                let mut tuple = Vec::with_capacity(streams.len());
+                let mut saw_nil = false;
+                for stream in streams.iter_mut() {
+                    match stream.try_next()? {
+                        KValue::Null => {
+                            saw_nil = true;
+                            break;
+                        }
+                        value => tuple.push(value),
+                    }
+                }
+
+                // the incomplete tuple is yielded as well
+                if saw_nil {
+                    self.streams = None;
+                    self.repeats -= 1.0;
+                }
+                return Ok(Some(KList::from_slice(&tuple).into()));
+            }
+
+            if self.repeats <= 0.0 {
+                return Ok(None);
+            }
+            // the patterns continue from where they stopped
+            let streams = self.list.data().iter().cloned().map(Source::new).collect();
+            self.streams = Some(streams);
+        }
+    }
+
+    fn copy(&self) -> Result<Self> {
+        let streams = match &self.streams {
+            Some(streams) => Some(
+                streams
+                    .iter()
+                    .map(Source::copy)
+                    .collect::<Result<Vec<Source>>>()?,
+            ),
+            None => None,
+        };
+
+        Ok(Self {
+            list: self.list.clone(),
+            repeats: self.repeats,
+            streams,
+        })
+    }
+}
+
+pub(super) fn pwalk(ctx: &mut CallContext) -> Result<KValue> {
+    let args = ctx.args();
+    Ok(make_expanding(Walk {
+        list: list(&arg(args, 0), "pwalk")?,
+        steps: Source::new(arg(args, 1)),
+        directions: Source::or(arg(args, 2), 1),
+        start: Source::or(arg(args, 3), 0),
+        state: None,
+    }))
+}
+
+struct Walk {
+    list: KList,
+    steps: Source,
+    directions: Source,
+    start: Source,
+    // the index, the step and the direction, after the walk is started
+    state: Option<(f64, Option<f64>, f64)>,
+}
+
+impl Walk {
+    fn direction(&mut self) -> Result<f64> {
+        number(&or(self.directions.try_next()?, 1), "pwalk")
+    }
+}
+
+impl Items for Walk {
+    fn next_item(&mut self) -> Result<Option<KValue>> {
+        let size = self.list.size() as f64;
+
+        let (index, step) = match self.state {
+            None => {
+                let index = number(&self.start.try_next()?, "pwalk")?;
+                let direction = self.direction()?;
+                let step = self.steps.next_number("pwalk")?;
+                self.state = Some((index, step, direction));
+                (index, step)
+            }
+            // it moves after the item is yielded
+            Some((index, Some(step), direction)) => {
+                let mut step = step * direction;
+                let mut direction = direction;
+                if index + step < 0.0 || index + step >= size {
+                    direction = self.direction()?;
+                    step = step.abs() * if direction >= 0.0 { 1.0 } else { -1.0 };
+                }
+                let index = (index + step).rem_euclid(size);
+                let step = self.steps.next_number("pwalk")?;
+                self.state = Some((index, step, direction));
+                (index, step)
+            }
+            Some((_, None, _)) => return Ok(None),
+        };
+
+        if step.is_none() || index < 0.0 {
+            return Ok(None);
+        }
+        match self.list.data().get(index as usize) {
+            Some(KValue::Null) | None => Ok(None),
+            Some(item) => Ok(Some(item.clone())),
+        }
+    }
+
+    fn copy(&self) -> Result<Self> {
+        Ok(Self {
+            list: self.list.clone(),
+            steps: self.steps.copy()?,
+            directions: self.directions.copy()?,
+            start: self.start.copy()?,
+            state: self.state,
+        })
+    }
+}
+
+pub(super) fn pclump(ctx: &mut CallContext) -> Result<KValue> {
+    let args = ctx.args();
+    Ok(make_pattern(Clump {
+        size: number(&arg(args, 0), "pclump")?,
+        pattern: iterator(&arg(args, 1), "pclump")?,
+        is_done: false,
+    }))
+}
+
+struct Clump {
+    size: f64,
+    pattern: KIterator,
+    is_done: bool,
+}
+
+impl Generator for Clump {
+    fn generate(&mut self) -> Result<Option<KValue>> {
+        if self.is_done {
+            return Ok(None);
+        }
+
+        let mut clump = Vec::new();
+        loop {
+            match next_value(&mut self.pattern)? {
+                // the incomplete clump is yielded as well
+                KValue::Null => {
+                    self.is_done = true;
+                    return Ok(Some(KList::from_slice(&clump).into()));
+                }
+                value => clump.push(value),
+            }
+            if clump.len() as f64 == self.size {
+                return Ok(Some(KList::from_slice(&clump).into()));
+            }
+        }
+    }
+
+    fn copy(&self) -> Result<Self> {
+        Ok(Self {
+            size: self.size,
+            pattern: self.pattern.make_copy()?,
+            is_done: self.is_done,
+        })
+    }
+}
+
+pub(super) fn pgeom(ctx: &mut CallContext) -> Result<KValue> {
+    let args = ctx.args();
+    Ok(make_pattern(Geom {
+        value: knumber(&or(arg(args, 0), 0), "pgeom")?,
+        grow: Source::or(arg(args, 1), 1),
+        length: number(&or(arg(args, 2), f64::INFINITY), "pgeom")?,
+        counter: 0.0,
+    }))
+}
+
+struct Geom {
+    value: KNumber,
+    grow: Source,
+    length: f64,
+    counter: f64,
+}
+
+impl Generator for Geom {
+    fn generate(&mut self) -> Result<Option<KValue>> {
+        if self.counter >= self.length {
+            return Ok(None);
+        }
+
+        match self.grow.try_next()? {
+            KValue::Null => Ok(None),
+            grow => {
+                let value = self.value;
+                self.value = value * knumber(&grow, "pgeom")?;
+                self.counter += 1.0;
+                Ok(Some(value.into()))
+            }
+        }
+    }
+
+    fn copy(&self) -> Result<Self> {
+        Ok(Self {
+            value: self.value,
+            grow: self.grow.copy()?,
+            length: self.length,
+            counter: self.counter,
+        })
+    }
+}
+
+pub(super) fn pseries(ctx: &mut CallContext) -> Result<KValue> {
+    let args = ctx.args();
+    Ok(make_pattern(Series {
+        start: Source::or(arg(args, 0), 0),
+        steps: Source::or(arg(args, 1), 1),
+        length: Source::or(arg(args, 2), f64::INFINITY),
+        state: None,
+    }))
+}
+
+struct Series {
+    start: Source,
+    steps: Source,
+    length: Source,
+    // the current value and the remaining length, after the first value is requested
+    state: Option<(KNumber, f64)>,
+}
+
+impl Generator for Series {
+    fn generate(&mut self) -> Result<Option<KValue>> {
+        let (value, length) = match self.state {
+            Some(state) => state,
+            None => (
+                knumber(&self.start.try_next()?, "pseries")?,
+                number(&self.length.try_next()?, "pseries")?,
+            ),
+        };
+        self.state = Some((value, length));
+        if length <= 0.0 {
+            return Ok(None);
+        }
+
+        match self.steps.try_next()? {
+            KValue::Null => Ok(None),
+            step => {
+                self.state = Some((value + knumber(&step, "pseries")?, length - 1.0));
+                Ok(Some(value.into()))
+            }
+        }
+    }
+
+    fn copy(&self) -> Result<Self> {
+        Ok(Self {
+            start: self.start.copy()?,
+            steps: self.steps.copy()?,
+            length: self.length.copy()?,
+            state: self.state,
+        })
+    }
+}
+
+pub(super) fn prorate(ctx: &mut CallContext) -> Result<KValue> {
+    let args = ctx.args();
+    Ok(make_pattern(Rorate {
+        proportions: Source::new(arg(args, 0)),
+        values: Source::or(arg(args, 1), 1),
+        pending: Vec::new(),
+    }))
+}
+
+/// Splits each value of the pattern in the proportion, i.e. `c` into `c` and `1 - c`, or into the
+/// parts of the list of proportions.
+struct Rorate {
+    proportions: Source,
+    values: Source,
+    // the parts of the current value in the reverse order
+    pending: Vec<KNumber>,
+}
+
+impl Generator for Rorate {
+    fn generate(&mut self) -> Result<Option<KValue>> {
+        if let Some(part) = self.pending.pop() {
+            return Ok(Some(part.into()));
+        }
+
+        let value = self.values.try_next()?;
+        let proportion = self.proportions.try_next()?;
+        if matches!(value, KValue::Null) || matches!(proportion, KValue::Null) {
+            return Ok(None);
+        }
+        let value = knumber(&value, "prorate")?;
+
+        self.pending = match proportion {
+            KValue::List(parts) => parts
+                .data()
+                .iter()
+                .map(|part| Ok(knumber(part, "prorate")? * value))
+                .collect::<Result<Vec<KNumber>>>()?,
+            proportion => {
+                let proportion = knumber(&proportion, "prorate")?;
+                vec![proportion * value, (KNumber::I64(1) - proportion) * value]
+            }
+        };
+        self.pending.reverse();
+
+        self.generate()
+    }
+
+    fn copy(&self) -> Result<Self> {
+        Ok(Self {
+            proportions: self.proportions.copy()?,
+            values: self.values.copy()?,
+            pending: self.pending.clone(),
+        })
+    }
+}
@@ -0,0 +1,460 @@
+//! Native implementations of the patterns of the core library (`koto/pattern.koto`).
+//!
+//! They follow the Koto implementations step by step, including the order in which the argument
+//! patterns are pulled and the random numbers are drawn, so the tests of the library are their
+//! conformance suite. As the Koto generators, the patterns are lazy: nothing is pulled before the
+//! first value is requested.
+use std::cell::RefCell;
+
+use koto::{prelude::*, runtime::Result};
+
+use super::random_module;
+use crate::orchestrator::rng;
+
+mod list;
+mod random;
+mod repetition;
+mod rhythm;
+
+type PatternFn = fn(&mut CallContext) -> Result<KValue>;
+
+const PATTERNS: &[(&str, PatternFn)] = &[
+    ("pbeta", random::pbeta),
+    ("pbrown", random::pbrown),
+    ("pgbrown", random::pgbrown),
+    ("pcauchy", random::pcauchy),
+    ("pclump", list::pclump),
+    ("pclutch", repetition::pclutch),
+    ("pconst", repetition::pconst),
+    ("pdup", repetition::pdup),
+    ("peuclid", rhythm::peuclid),
+    ("pexprand", random::pexprand),
+    ("pgauss", random::pgauss),
+    ("pgeom", list::pgeom),
+    ("pwhite", random::pwhite),
+    ("plprand", random::plprand),
+    ("phprand", random::phprand),
+    ("pmeanrand", random::pmeanrand),
+    ("pindex", list::pindex),
+    ("pfsm", list::pfsm),
+    ("place", list::place),
+    ("pmarkov", list::pmarkov),
+    ("ppoisson", random::ppoisson),
+    ("prand", list::prand),
+    ("prorate", list::prorate),
+    ("pseq", list::pseq),
+    ("pser", list::pser),
+    ("pseries", list::pseries),
+    ("pshuf", list::pshuf),
+    ("pslide", list::pslide),
+    ("psubdivide", repetition::psubdivide),
+    ("pswitch", list::pswitch),
+    ("ptuple", list::ptuple),
+    ("pwalk", list::pwalk),
+    ("pwrand", list::pwrand),
+    ("pxrand", list::pxrand),
+    ("pn", repetition::pn),
+];
+
+pub(super) fn add_patterns(module: &KMap) {
+    for (name, function) in PATTERNS {
+        module.add_fn(name, *function);
+    }
+}
+
+/// The `pseq` pattern over the list, as `pseq(list)` in Koto.
+pub(super) fn seq(list: KList) -> KValue {
+    list::seq(list)
+}
+
+/// The code, which replaces the patterns exported by the core library with the native ones.
+pub(super) fn import_code() -> String {
+    let names: Vec<&str> = PATTERNS.iter().map(|(name, _)| *name).collect();
+    format!("from kotoist import {}", names.join(", "))
+}
+
+/// The state machine of a pattern.
+trait Generator: Sized + Send + Sync + 'static {
+    /// Returns the next value or `None`, when the pattern ends.
+    fn generate(&mut self) -> Result<Option<KValue>>;
+
+    /// Makes an independent copy, which continues from the current state.
+    fn copy(&self) -> Result<Self>;
+}
+
+fn make_pattern(generator: impl Generator) -> KValue {
+    KIterator::new(Pattern {
+        generator,
+        is_done: false,
+    })
+    .into()
+}
+
+struct Pattern<G> {
+    generator: G,
+    // the pattern isn't resumed after it ends or fails, as a Koto generator
+    is_done: bool,
+}
+
+impl<G: Generator> KotoIterator for Pattern<G> {
+    fn make_copy(&self) -> Result<KIterator> {
+        Ok(KIterator::new(Self {
+            generator: self.generator.copy()?,
+            is_done: self.is_done,
+        }))
+    }
+}
+
+impl<G: Generator> Iterator for Pattern<G> {
+    type Item = KIteratorOutput;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.is_done {
+            return None;
+        }
+
+        match self.generator.generate() {
+            Ok(Some(value)) => Some(KIteratorOutput::Value(value)),
+            Ok(None) => {
+                self.is_done = true;
+                None
+            }
+            Err(e) => {
+                self.is_done = true;
+                Some(KIteratorOutput::Error(e))
+            }
+        }
+    }
+}
+
+/// The source of the items of a list pattern. The nested patterns among the items are expanded
+/// by [`Expanding`].
+trait Items: Sized + Send + Sync + 'static {
+    /// Returns the next item or `None`, when the pattern ends.
+    fn next_item(&mut self) -> Result<Option<KValue>>;
+
+    fn copy(&self) -> Result<Self>;
+}
+
+/// Yields the items, replacing each nested pattern with the values of its copy.
+struct Expanding<I> {
+    items: I,
+    nested: Nested,
+}
+
+fn make_expanding(items: impl Items) -> KValue {
+    make_pattern(Expanding {
+        items,
+        nested: Nested::default(),
+    })
+}
+
+impl<I: Items> Generator for Expanding<I> {
+    fn generate(&mut self) -> Result<Option<KValue>> {
+        loop {
+            if let Some(value) = self.nested.next()? {
+                return Ok(Some(value));
+            }
+
+            match self.items.next_item()? {
+                Some(item) => {
+                    if let Some(value) = self.nested.start(item)? {
+                        return Ok(Some(value));
+                    }
+                }
+                None => return Ok(None),
+            }
+        }
+    }
+
+    fn copy(&self) -> Result<Self> {
+        Ok(Self {
+            items: self.items.copy()?,
+            nested: self.nested.copy()?,
+        })
+    }
+}
+
+/// The copy of the nested pattern, which is pulled until it yields `null`.
+#[derive(Default)]
+struct Nested(Option<KIterator>);
+
+impl Nested {
+    /// Returns the item itself, or the first value of the copy, if it's a pattern.
+    fn start(&mut self, item: KValue) -> Result<Option<KValue>> {
+        match item {
+            KValue::Iterator(iterator) => {
+                self.0 = Some(iterator.make_copy()?);
+                self.next()
+            }
+            item => Ok(Some(item)),
+        }
+    }
+
+    fn next(&mut self) -> Result<Option<KValue>> {
+        let value = match self.0.as_mut() {
+            Some(iterator) => next_value(iterator)?,
+            None => return Ok(None),
+        };
+
+        if matches!(value, KValue::Null) {
+            self.0 = None;
+            Ok(None)
+        } else {
+            Ok(Some(value))
+        }
+    }
+
+    fn copy(&self) -> Result<Self> {
+        self.0
+            .as_ref()
+            .map(|iterator| iterator.make_copy())
+            .transpose()
+            .map(Self)
+    }
+}
+
+/// The equivalent of `as_iter`: the values of the pattern until it yields `null`, or the value
+/// repeated endlessly.
+enum Source {
+    Value(KValue),
+    Iterator { iterator: KIterator, is_done: bool },
+}
+
+impl Source {
+    fn new(value: KValue) -> Self {
+        match value {
+            KValue::Iterator(iterator) => Self::Iterator {
+                iterator,
+                is_done: false,
+            },
+            value => Self::Value(value),
+        }
+    }
+
+    /// The equivalent of `as_iter value or default`.
+    fn or(value: KValue, default: impl Into<KValue>) -> Self {
+        Self::new(or(value, default))
+    }
+
+    /// Returns `null`, when the pattern ends.
+    fn try_next(&mut self) -> Result<KValue> {
+        match self {
+            Self::Value(value) => Ok(value.clone()),
+            Self::Iterator { is_done: true, .. } => Ok(KValue::Null),
+            Self::Iterator { iterator, is_done } => {
+                let value = next_value(iterator)?;
+                *is_done = matches!(value, KValue::Null);
+                Ok(value)
+            }
+        }
+    }
+
+    /// Returns `None`, when the pattern ends.
+    fn next_number(&mut self, fn_name: &str) -> Result<Option<f64>> {
+        match self.try_next()? {
+            KValue::Null => Ok(None),
+            value => number(&value, fn_name).map(Some),
+        }
+    }
+
+    fn copy(&self) -> Result<Self> {
+        match self {
+            Self::Value(value) => Ok(Self::Value(value.clone())),
+            Self::Iterator { iterator, is_done } => Ok(Self::Iterator {
+                iterator: iterator.make_copy()?,
+                is_done: *is_done,
+            }),
+        }
+    }
+}
+
+thread_local! {
+    // calls the `random` module outside of the players, it's shared by all the patterns
+    static FALLBACK_VM: RefCell<KotoVm> = RefCell::new(KotoVm::default());
+}
+
+/// The random numbers of the patterns. While a player pulls its pattern, they're drawn from the
+/// generator of the player, so the output follows its seed. Otherwise (e.g. while the script is
+/// evaluated or in the tests) they're drawn from the `random` module of the prelude, so
+/// `random.seed` applies to them.
+#[derive(Clone)]
+struct Random {
+    number: KValue,
+    pick: KValue,
+}
+
+impl Random {
+    fn new(ctx: &mut CallContext, fn_name: &str) -> Result<Self> {
+        let module = match ctx.vm.prelude().get("random") {
+            Some(KValue::Map(module)) => module,
+            _ => return runtime_error!("kotoist.{}: the random module isn't available", fn_name),
+        };
+        let function = |name: &str| match module.get(name) {
+            Some(function) if function.is_callable() => Ok(function),
+            _ => runtime_error!("kotoist.{}: random.{} isn't available", fn_name, name),
+        };
+
+        Ok(Self {
+            number: function("number")?,
+            pick: function("pick")?,
+        })
+    }
+
+    /// The random number in the range 0..1.
+    fn number(&self) -> Result<f64> {
+        if let Some(number) = rng::number() {
+            return Ok(number);
+        }
+
+        let number =
+            FALLBACK_VM.with(|vm| vm.borrow_mut().call_function(self.number.clone(), &[]))?;
+        match number {
+            KValue::Number(number) => Ok(f64::from(number)),
+            other => runtime_error!(
+                "random.number: expected a number, but found '{}'",
+                other.type_as_string()
+            ),
+        }
+    }
+
+    /// The random item of the container, or `null`, if it's empty.
+    fn pick(&self, container: &KValue) -> Result<KValue> {
+        if let Some(item) = random_module::pick(container) {
+            return Ok(item);
+        }
+
+        FALLBACK_VM.with(|vm| {
+            vm.borrow_mut()
+                .call_function(self.pick.clone(), &[container.clone()])
+        })
+    }
+
+    /// The equivalent of `rrand`.
+    fn range(&self, lo: f64, hi: f64) -> Result<f64> {
+        Ok(self.number()? * (hi - lo) + lo)
+    }
+
+    /// The equivalent of `number.rand2`.
+    fn bipolar(&self, value: f64) -> Result<f64> {
+        Ok(self.number()? * value * 2.0 - value)
+    }
+}
+
+/// The equivalent of `iterator.try_next`. Returns `null`, when the iterator ends.
+fn next_value(iterator: &mut KIterator) -> Result<KValue> {
+    match iterator.next() {
+        Some(KIteratorOutput::Value(value)) => Ok(value),
+        Some(KIteratorOutput::ValuePair(first, second)) => {
+            Ok(KValue::Tuple(KTuple::from(vec![first, second])))
+        }
+        Some(KIteratorOutput::Error(e)) => Err(e),
+        None => Ok(KValue::Null),
+    }
+}
+
+fn arg(args: &[KValue], index: usize) -> KValue {
+    args.get(index).cloned().unwrap_or(KValue::Null)
+}
+
+/// The equivalent of Koto's `value or default`.
+fn or(value: KValue, default: impl Into<KValue>) -> KValue {
+    if is_truthy(&value) {
+        value
+    } else {
+        default.into()
+    }
+}
+
+fn is_truthy(value: &KValue) -> bool {
+    !matches!(value, KValue::Null | KValue::Bool(false))
+}
+
+fn number(value: &KValue, fn_name: &str) -> Result<f64> {
+    match value {
+        KValue::Number(number) => Ok(f64::from(number)),
+        other => runtime_error!(
+            "kotoist.{}: expected a number, but found '{}'",
+            fn_name,
+            other.type_as_string()
+        ),
+    }
+}
+
+fn knumber(value: &KValue, fn_name: &str) -> Result<KNumber> {
+    match value {
+        KValue::Number(number) => Ok(*number),
+        other => runtime_error!(
+            "kotoist.{}: expected a number, but found '{}'",
+            fn_name,
+            other.type_as_string()
+        ),
+    }
+}
+
+fn list(value: &KValue, fn_name: &str) -> Result<KList> {
+    match value {
+        KValue::List(list) => Ok(list.clone()),
+        other => runtime_error!(
+            "kotoist.{}: expected a list, but found '{}'",
+            fn_name,
+            other.type_as_string()
+        ),
+    }
+}
+
+fn iterator(value: &KValue, fn_name: &str) -> Result<KIterator> {
+    match value {
+        KValue::Iterator(iterator) => Ok(iterator.clone()),
+        other => runtime_error!(
+            "kotoist.{}: expected a pattern, but found '{}'",
+            fn_name,
+            other.type_as_string()
+        ),
+    }
+}
+
+/// The item at the position wrapped around the size of the list. The list shouldn't be empty.
+fn wrapped_item(list: &KList, position: f64) -> KValue {
+    let data = list.data();
+    let index = position.floor().rem_euclid(data.len() as f64) as usize;
+    data[index].clone()
+}
+
+fn copy_value(value: &KValue) -> Result<KValue> {
+    match value {
+        KValue::Iterator(iterator) => Ok(iterator.make_copy()?.into()),
+        value => Ok(value.clone()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::interpreter::interpreter::KOTO_LIB_CODE;
+    use crate::interpreter::random_module::make_module as make_random_module;
+
+    // runs the tests of the core library against the currently exported patterns
+    const RUN_TESTS_CODE: &str = "test.run_tests koto.exports()";
+
+    #[test]
+    fn test_conformance() {
+        let mut koto = Koto::with_settings(KotoSettings {
+            run_tests: true,
+            ..Default::default()
+        });
+        let module = KMap::new();
+        add_patterns(&module);
+        koto.prelude().insert("kotoist", module);
+        koto.prelude().insert("random", make_random_module());
+
+        if let Err(e) = koto.compile_and_run(KOTO_LIB_CODE) {
+            panic!("the Koto patterns should pass the tests: {}", e);
+        }
+        koto.compile_and_run(CompileArgs::new(&import_code()).export_top_level_ids(true))
+            .expect("importing the native patterns should not fail");
+        if let Err(e) = koto.compile_and_run(RUN_TESTS_CODE) {
+            panic!("the native patterns should pass the tests: {}", e);
+        }
+    }
+}
@@ -0,0 +1,556 @@
+//! The patterns of random values.
+use std::f64::consts::PI;
+
+use koto::{prelude::*, runtime::Result};
+
+use super::{arg, make_pattern, number, or, Generator, Random, Source};
+
+pub(super) fn pwhite(ctx: &mut CallContext) -> Result<KValue> {
+    Ok(make_pattern(White::new(ctx, "pwhite")?))
+}
+
+struct White {
+    lo: Source,
+    hi: Source,
+    length: f64,
+    random: Random,
+}
+
+impl White {
+    fn new(ctx: &mut CallContext, fn_name: &str) -> Result<Self> {
+        let args = ctx.args();
+        let lo = Source::or(arg(args, 0), 0);
+        let hi = Source::or(arg(args, 1), 1);
+        let length = number(&or(arg(args, 2), f64::INFINITY), fn_name)?;
+
+        Ok(Self {
+            lo,
+            hi,
+            length,
+            random: Random::new(ctx, fn_name)?,
+        })
+    }
+}
+
+impl Generator for White {
+    fn generate(&mut self) -> Result<Option<KValue>> {
+        if self.length <= 0.0 {
+            return Ok(None);
+        }
+
+        let hi = self.hi.next_number("pwhite")?;
+        let lo = self.lo.next_number("pwhite")?;
+        match (lo, hi) {
+            (Some(lo), Some(hi)) => {
+                self.length -= 1.0;
+                Ok(Some(self.random.range(lo, hi)?.into()))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn copy(&self) -> Result<Self> {
+        Ok(Self {
+            lo: self.lo.copy()?,
+            hi: self.hi.copy()?,
+            length: self.length,
+            random: self.random.clone(),
+        })
+    }
+}
+
+pub(super) fn plprand(ctx: &mut CallContext) -> Result<KValue> {
+    Ok(make_pattern(Pair::new(ctx, "plprand", f64::min)?))
+}
+
+pub(super) fn phprand(ctx: &mut CallContext) -> Result<KValue> {
+    Ok(make_pattern(Pair::new(ctx, "phprand", f64::max)?))
+}
+
+pub(super) fn pmeanrand(ctx: &mut CallContext) -> Result<KValue> {
+    Ok(make_pattern(Pair::new(ctx, "pmeanrand", |a, b| {
+        (a + b) * 0.5
+    })?))
+}
+
+/// Combines the values of two `pwhite` patterns with the same arguments.
+struct Pair {
+    first: White,
+    second: White,
+    length: f64,
+    combine: fn(f64, f64) -> f64,
+}
+
+impl Pair {
+    fn new(ctx: &mut CallContext, fn_name: &str, combine: fn(f64, f64) -> f64) -> Result<Self> {
+        let first = White::new(ctx, fn_name)?;
+        // the argument patterns are shared by both of them
+        let second = White {
+            lo: Source::or(arg(ctx.args(), 0), 0),
+            hi: Source::or(arg(ctx.args(), 1), 1),
+            length: first.length,
+            random: first.random.clone(),
+        };
+
+        Ok(Self {
+            length: first.length,
+            first,
+            second,
+            combine,
+        })
+    }
+}
+
+impl Generator for Pair {
+    fn generate(&mut self) -> Result<Option<KValue>> {
+        if self.length <= 0.0 {
+            return Ok(None);
+        }
+        self.length -= 1.0;
+
+        let first = self.first.generate()?;
+        let second = self.second.generate()?;
+        match (first, second) {
+            (Some(KValue::Number(first)), Some(KValue::Number(second))) => {
+                Ok(Some((self.combine)(first.into(), second.into()).into()))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn copy(&self) -> Result<Self> {
+        Ok(Self {
+            first: self.first.copy()?,
+            second: self.second.copy()?,
+            length: self.length,
+            combine: self.combine,
+        })
+    }
+}
+
+pub(super) fn pbrown(ctx: &mut CallContext) -> Result<KValue> {
+    Ok(make_pattern(Brown::new(ctx, "pbrown", |cur, step| {
+        cur + step
+    })?))
+}
+
+pub(super) fn pgbrown(ctx: &mut CallContext) -> Result<KValue> {
+    Ok(make_pattern(Brown::new(ctx, "pgbrown", |cur, step| {
+        cur * (1.0 + step)
+    })?))
+}
+
+/// The brownian motion, where the next value is made of the current one and the random step.
+struct Brown {
+    lo: Source,
+    hi: Source,
+    step: Source,
+    length: f64,
+    current: Option<f64>,
+    advance: fn(f64, f64) -> f64,
+    random: Random,
+    fn_name: &'static str,
+}
+
+impl Brown {
+    fn new(
+        ctx: &mut CallContext,
+        fn_name: &'static str,
+        advance: fn(f64, f64) -> f64,
+    ) -> Result<Self> {
+        let args = ctx.args();
+        let lo = Source::or(arg(args, 0), 0);
+        let hi = Source::or(arg(args, 1), 1);
+        let step = Source::or(arg(args, 2), 0.125);
+        let length = number(&or(arg(args, 3), f64::INFINITY), fn_name)?;
+
+        Ok(Self {
+            lo,
+            hi,
+            step,
+            length,
+            current: None,
+            advance,
+            random: Random::new(ctx, fn_name)?,
+            fn_name,
+        })
+    }
+
+    fn next_bounds(&mut self) -> Result<Option<(f64, f64, f64)>> {
+        let lo = self.lo.next_number(self.fn_name)?;
+        let hi = self.hi.next_number(self.fn_name)?;
+        let step = self.step.next_number(self.fn_name)?;
+
+        Ok(lo.zip(hi).zip(step).map(|((lo, hi), step)| (lo, hi, step)))
+    }
+}
+
+impl Generator for Brown {
+    fn generate(&mut self) -> Result<Option<KValue>> {
+        let current = match self.current {
+            Some(current) => current,
+            None => {
+                // it starts from a random value in the initial bounds
+                let bounds = self.next_bounds()?;
+                let current = match bounds {
+                    Some((lo, hi, _)) => self.random.range(lo, hi)?,
+                    None => 0.0,
+                };
+                self.current = Some(current);
+                if bounds.is_none() {
+                    self.length = 0.0;
+                    return Ok(Some(KValue::Null));
+                }
+                current
+            }
+        };
+        if self.length <= 0.0 {
+            return Ok(None);
+        }
+
+        match self.next_bounds()? {
+            Some((lo, hi, step)) => {
+                let next = (self.advance)(current, self.random.bipolar(step)?);
+                let current = fold(next, lo, hi);
+                self.current = Some(current);
+                self.length -= 1.0;
+                Ok(Some(current.into()))
+            }
+            None => {
+                self.length = 0.0;
+                Ok(Some(KValue::Null))
+            }
+        }
+    }
+
+    fn copy(&self) -> Result<Self> {
+        Ok(Self {
+            lo: self.lo.copy()?,
+            hi: self.hi.copy()?,
+            step: self.step.copy()?,
+            length: self.length,
+            current: self.current,
+            advance: self.advance,
+            random: self.random.clone(),
+            fn_name: self.fn_name,
+        })
+    }
+}
+
+/// The equivalent of `number.fold`: the value is reflected from the bounds until it's in the
+/// range.
+fn fold(value: f64, lo: f64, hi: f64) -> f64 {
+    let x = value - lo;
+
+    // avoid the divide if possible
+    if value >= hi {
+        let temp = hi + hi - value;
+        if temp >= lo {
+            return temp;
+        }
+    } else if value < lo {
+        let temp = lo + lo - value;
+        if temp < hi {
+            return temp;
+        }
+    }
+
+    if hi == lo {
+        return lo;
+    }
+
+    let range = hi - lo;
+    let range2 = range + range;
+    let mut c = x - range2 * (x / range2).floor();
+    if c >= range {
+        c = range2 - c;
+    }
+
+    c + lo
+}
+
+pub(super) fn pbeta(ctx: &mut CallContext) -> Result<KValue> {
+    let args = ctx.args();
+    let lo = Source::or(arg(args, 0), 0);
+    let hi = Source::or(arg(args, 1), 1);
+    let prob1 = Source::or(arg(args, 2), 1);
+    let prob2 = Source::or(arg(args, 3), 1);
+    let length = number(&or(arg(args, 4), f64::INFINITY), "pbeta")?;
+
+    Ok(make_pattern(Beta {
+        lo,
+        hi,
+        prob1,
+        prob2,
+        length,
+        random: Random::new(ctx, "pbeta")?,
+    }))
+}
+
+/// The Eulerian beta distribution.
+struct Beta {
+    lo: Source,
+    hi: Source,
+    prob1: Source,
+    prob2: Source,
+    length: f64,
+    random: Random,
+}
+
+impl Generator for Beta {
+    fn generate(&mut self) -> Result<Option<KValue>> {
+        if self.length <= 0.0 {
+            return Ok(None);
+        }
+
+        let prob1 = self.prob1.next_number("pbeta")?;
+        let prob2 = self.prob2.next_number("pbeta")?;
+        let (rprob1, rprob2) = match (prob1, prob2) {
+            (Some(prob1), Some(prob2)) => (prob1.recip(), prob2.recip()),
+            _ => {
+                self.length = 0.0;
+                return Ok(Some(KValue::Null));
+            }
+        };
+
+        let lo = self.lo.next_number("pbeta")?;
+        let hi = self.hi.next_number("pbeta")?;
+        let (lo, hi) = match (lo, hi) {
+            (Some(lo), Some(hi)) => (lo, hi),
+            _ => {
+                self.length = 0.0;
+                return Ok(Some(KValue::Null));
+            }
+        };
+
+        let mut sum = 2.0;
+        let mut temp = 0.0;
+        while sum > 1.0 {
+            temp = self.random.number()?.powf(rprob1);
+            sum = temp + self.random.number()?.powf(rprob2);
+        }
+        self.length -= 1.0;
+
+        Ok(Some(((temp / sum) * (hi - lo) + lo).into()))
+    }
+
+    fn copy(&self) -> Result<Self> {
+        Ok(Self {
+            lo: self.lo.copy()?,
+            hi: self.hi.copy()?,
+            prob1: self.prob1.copy()?,
+            prob2: self.prob2.copy()?,
+            length: self.length,
+            random: self.random.clone(),
+        })
+    }
+}
+
+pub(super) fn pcauchy(ctx: &mut CallContext) -> Result<KValue> {
+    let args = ctx.args();
+    let mean = Source::or(arg(args, 0), 0);
+    let spread = Source::or(arg(args, 1), 1);
+    let length = number(&or(arg(args, 2), f64::INFINITY), "pcauchy")?;
+
+    Ok(make_pattern(Cauchy {
+        mean,
+        spread,
+        length,
+        random: Random::new(ctx, "pcauchy")?,
+    }))
+}
+
+/// The Cauchy distribution.
+struct Cauchy {
+    mean: Source,
+    spread: Source,
+    length: f64,
+    random: Random,
+}
+
+impl Generator for Cauchy {
+    fn generate(&mut self) -> Result<Option<KValue>> {
+        if self.length <= 0.0 {
+            return Ok(None);
+        }
+
+        let mean = self.mean.next_number("pcauchy")?;
+        let spread = self.spread.next_number("pcauchy")?;
+        let (mean, spread) = match (mean, spread) {
+            (Some(mean), Some(spread)) => (mean, spread),
+            _ => {
+                self.length = 0.0;
+                return Ok(Some(KValue::Null));
+            }
+        };
+
+        let mut ran = 0.5;
+        while ran == 0.5 {
+            ran = self.random.number()?;
+        }
+        self.length -= 1.0;
+
+        Ok(Some(((ran * PI).tan() * spread + mean).into()))
+    }
+
+    fn copy(&self) -> Result<Self> {
+        Ok(Self {
+            mean: self.mean.copy()?,
+            spread: self.spread.copy()?,
+            length: self.length,
+            random: self.random.clone(),
+        })
+    }
+}
+
+pub(super) fn pexprand(ctx: &mut CallContext) -> Result<KValue> {
+    let args = ctx.args();
+    let lo = Source::or(arg(args, 0), 0.0001);
+    let hi = Source::or(arg(args, 1), 1);
+    let length = number(&or(arg(args, 2), f64::INFINITY), "pexprand")?;
+
+    Ok(make_pattern(ExpRand {
+        lo,
+        hi,
+        length,
+        random: Random::new(ctx, "pexprand")?,
+    }))
+}
+
+/// The exponential distribution.
+struct ExpRand {
+    lo: Source,
+    hi: Source,
+    length: f64,
+    random: Random,
+}
+
+impl Generator for ExpRand {
+    fn generate(&mut self) -> Result<Option<KValue>> {
+        if self.length <= 0.0 {
+            return Ok(None);
+        }
+
+        let hi = self.hi.next_number("pexprand")?;
+        let lo = self.lo.next_number("pexprand")?;
+        match (lo, hi) {
+            (Some(lo), Some(hi)) => {
+                self.length -= 1.0;
+                // the equivalent of `exprand`
+                let value = lo * ((hi / lo).ln() * self.random.number()?).exp();
+                Ok(Some(value.into()))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn copy(&self) -> Result<Self> {
+        Ok(Self {
+            lo: self.lo.copy()?,
+            hi: self.hi.copy()?,
+            length: self.length,
+            random: self.random.clone(),
+        })
+    }
+}
+
+pub(super) fn pgauss(ctx: &mut CallContext) -> Result<KValue> {
+    let args = ctx.args();
+    let mean = Source::or(arg(args, 0), 0);
+    let dev = Source::or(arg(args, 1), 1);
+    let length = number(&or(arg(args, 2), f64::INFINITY), "pgauss")?;
+
+    Ok(make_pattern(Gauss {
+        mean,
+        dev,
+        length,
+        random: Random::new(ctx, "pgauss")?,
+    }))
+}
+
+/// The Gaussian distribution.
+struct Gauss {
+    mean: Source,
+    dev: Source,
+    length: f64,
+    random: Random,
+}
+
+impl Generator for Gauss {
+    fn generate(&mut self) -> Result<Option<KValue>> {
+        if self.length <= 0.0 {
+            return Ok(None);
+        }
+
+        let dev = self.dev.next_number("pgauss")?;
+        let mean = self.mean.next_number("pgauss")?;
+        match (mean, dev) {
+            (Some(mean), Some(dev)) => {
+                let a = (-2.0 * self.random.number()?.ln()).sqrt();
+                let b = (2.0 * PI * self.random.number()?).sin();
+                self.length -= 1.0;
+                Ok(Some((a * b * dev + mean).into()))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn copy(&self) -> Result<Self> {
+        Ok(Self {
+            mean: self.mean.copy()?,
+            dev: self.dev.copy()?,
+            length: self.length,
+            random: self.random.clone(),
+        })
+    }
+}
+
+pub(super) fn ppoisson(ctx: &mut CallContext) -> Result<KValue> {
+    let args = ctx.args();
+    let mean = Source::or(arg(args, 0), 1);
+    let length = number(&or(arg(args, 1), f64::INFINITY), "ppoisson")?;
+
+    Ok(make_pattern(Poisson {
+        mean,
+        length,
+        random: Random::new(ctx, "ppoisson")?,
+    }))
+}
+
+/// The Poisson distribution (the positive integers).
+struct Poisson {
+    mean: Source,
+    length: f64,
+    random: Random,
+}
+
+impl Generator for Poisson {
+    fn generate(&mut self) -> Result<Option<KValue>> {
+        if self.length <= 0.0 {
+            return Ok(None);
+        }
+
+        let mean = match self.mean.next_number("ppoisson")? {
+            Some(mean) => mean,
+            None => return Ok(None),
+        };
+        let mut inc: i64 = 0;
+        let mut test = self.random.number()?;
+        let temp = (-mean).exp();
+        while test > temp {
+            inc += 1;
+            test *= self.random.number()?;
+        }
+        self.length -= 1.0;
+
+        Ok(Some(KValue::Number(inc.into())))
+    }
+
+    fn copy(&self) -> Result<Self> {
+        Ok(Self {
+            mean: self.mean.copy()?,
+            length: self.length,
+            random: self.random.clone(),
+        })
+    }
+}
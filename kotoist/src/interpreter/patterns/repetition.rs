@@ -0,0 +1,277 @@
+//! The patterns, which repeat or split the values of other patterns.
+use koto::{prelude::*, runtime::Result};
+
+use super::{
+    arg, copy_value, is_truthy, iterator, knumber, make_expanding, make_pattern, next_value,
+    number, or, Generator, Items, Source,
+};
+
+pub(super) fn pn(ctx: &mut CallContext) -> Result<KValue> {
+    let args = ctx.args();
+    Ok(make_expanding(N {
+        pattern: arg(args, 0),
+        repeats: number(&arg(args, 1), "pn")?,
+    }))
+}
+
+struct N {
+    pattern: KValue,
+    repeats: f64,
+}
+
+impl Items for N {
+    fn next_item(&mut self) -> Result<Option<KValue>> {
+        if self.repeats <= 0.0 {
+            return Ok(None);
+        }
+
+        self.repeats -= 1.0;
+        Ok(Some(self.pattern.clone()))
+    }
+
+    fn copy(&self) -> Result<Self> {
+        Ok(Self {
+            pattern: copy_value(&self.pattern)?,
+            repeats: self.repeats,
+        })
+    }
+}
+
+pub(super) fn pdup(ctx: &mut CallContext) -> Result<KValue> {
+    let args = ctx.args();
+    let times = match arg(args, 0) {
+        KValue::Iterator(times) => Times::Pattern(times),
+        times => Times::Once(Some(number(&times, "pdup")?)),
+    };
+
+    Ok(make_pattern(Dup {
+        times,
+        pattern: iterator(&arg(args, 1), "pdup")?,
+        value: KValue::Null,
+        remaining: 0.0,
+    }))
+}
+
+enum Times {
+    /// The single value is repeated, it's taken after it's used.
+    Once(Option<f64>),
+    /// The number of repeats of each value.
+    Pattern(KIterator),
+}
+
+struct Dup {
+    times: Times,
+    pattern: KIterator,
+    value: KValue,
+    remaining: f64,
+}
+
+impl Generator for Dup {
+    fn generate(&mut self) -> Result<Option<KValue>> {
+        loop {
+            if self.remaining > 0.0 {
+                self.remaining -= 1.0;
+                return Ok(Some(self.value.clone()));
+            }
+
+            let times = match &mut self.times {
+                Times::Once(times) => match times.take() {
+                    Some(times) => times,
+                    None => return Ok(None),
+                },
+                Times::Pattern(times) => match next_value(times)? {
+                    KValue::Null => return Ok(None),
+                    times => number(&times, "pdup")?,
+                },
+            };
+            match next_value(&mut self.pattern)? {
+                KValue::Null => return Ok(None),
+                value => {
+                    self.value = value;
+                    self.remaining = times;
+                }
+            }
+        }
+    }
+
+    fn copy(&self) -> Result<Self> {
+        let times = match &self.times {
+            Times::Once(times) => Times::Once(*times),
+            Times::Pattern(times) => Times::Pattern(times.make_copy()?),
+        };
+
+        Ok(Self {
+            times,
+            pattern: self.pattern.make_copy()?,
+            value: self.value.clone(),
+            remaining: self.remaining,
+        })
+    }
+}
+
+pub(super) fn pclutch(ctx: &mut CallContext) -> Result<KValue> {
+    let args = ctx.args();
+    Ok(make_pattern(Clutch {
+        pattern: iterator(&arg(args, 0), "pclutch")?,
+        connected: Source::or(arg(args, 1), true),
+        value: KValue::Null,
+        is_done: false,
+    }))
+}
+
+/// Takes the next value of the pattern, when it's connected, otherwise repeats the previous one.
+struct Clutch {
+    pattern: KIterator,
+    connected: Source,
+    value: KValue,
+    is_done: bool,
+}
+
+impl Generator for Clutch {
+    fn generate(&mut self) -> Result<Option<KValue>> {
+        if self.is_done {
+            return Ok(None);
+        }
+
+        match self.connected.try_next()? {
+            KValue::Null => {
+                self.is_done = true;
+                Ok(Some(KValue::Null))
+            }
+            is_connected if is_truthy(&is_connected) => {
+                self.value = next_value(&mut self.pattern)?;
+                self.is_done = matches!(self.value, KValue::Null);
+                Ok(Some(self.value.clone()))
+            }
+            _ => Ok(Some(self.value.clone())),
+        }
+    }
+
+    fn copy(&self) -> Result<Self> {
+        Ok(Self {
+            pattern: self.pattern.make_copy()?,
+            connected: self.connected.copy()?,
+            value: self.value.clone(),
+            is_done: self.is_done,
+        })
+    }
+}
+
+pub(super) fn pconst(ctx: &mut CallContext) -> Result<KValue> {
+    let args = ctx.args();
+    Ok(make_pattern(Const {
+        sum: number(&arg(args, 0), "pconst")?,
+        pattern: Source::new(arg(args, 1)),
+        tolerance: number(&or(arg(args, 2), 0.001), "pconst")?,
+        elapsed: 0.0,
+        is_done: false,
+    }))
+}
+
+/// Yields the values of the pattern, until their sum reaches the limit. The last value is cut to
+/// fit the sum.
+struct Const {
+    sum: f64,
+    pattern: Source,
+    tolerance: f64,
+    elapsed: f64,
+    is_done: bool,
+}
+
+impl Generator for Const {
+    fn generate(&mut self) -> Result<Option<KValue>> {
+        if self.is_done {
+            return Ok(None);
+        }
+
+        let delta = match self.pattern.try_next()? {
+            KValue::Null => {
+                self.is_done = true;
+                return Ok(Some((self.sum - self.elapsed).into()));
+            }
+            delta => delta,
+        };
+
+        let next_elapsed = self.elapsed + number(&delta, "pconst")?;
+        if round(next_elapsed, self.tolerance) >= self.sum {
+            self.is_done = true;
+            Ok(Some((self.sum - self.elapsed).into()))
+        } else {
+            self.elapsed = next_elapsed;
+            Ok(Some(delta))
+        }
+    }
+
+    fn copy(&self) -> Result<Self> {
+        Ok(Self {
+            sum: self.sum,
+            pattern: self.pattern.copy()?,
+            tolerance: self.tolerance,
+            elapsed: self.elapsed,
+            is_done: self.is_done,
+        })
+    }
+}
+
+/// The equivalent of `number.round`.
+fn round(value: f64, quant: f64) -> f64 {
+    if quant == 0.0 {
+        value
+    } else {
+        (value / quant + 0.5).floor() * quant
+    }
+}
+
+pub(super) fn psubdivide(ctx: &mut CallContext) -> Result<KValue> {
+    let args = ctx.args();
+    Ok(make_pattern(Subdivide {
+        subdivisions: Source::new(arg(args, 0)),
+        durations: Source::new(arg(args, 1)),
+        duration: KNumber::I64(0),
+        remaining: 0,
+    }))
+}
+
+/// Splits each duration into the number of equal parts. Zero parts skip the duration.
+struct Subdivide {
+    subdivisions: Source,
+    durations: Source,
+    duration: KNumber,
+    remaining: usize,
+}
+
+impl Generator for Subdivide {
+    fn generate(&mut self) -> Result<Option<KValue>> {
+        loop {
+            if self.remaining > 0 {
+                self.remaining -= 1;
+                return Ok(Some(self.duration.into()));
+            }
+
+            let duration = self.durations.try_next()?;
+            let subdivision = self.subdivisions.try_next()?;
+            if matches!(duration, KValue::Null) || matches!(subdivision, KValue::Null) {
+                return Ok(None);
+            }
+            let duration = knumber(&duration, "psubdivide")?;
+            let subdivision = knumber(&subdivision, "psubdivide")?;
+
+            if f64::from(subdivision) > 1.0 {
+                self.duration = duration / subdivision;
+                self.remaining = f64::from(subdivision) as usize;
+            } else if f64::from(subdivision) > 0.0 {
+                self.duration = duration;
+                self.remaining = 1;
+            }
+        }
+    }
+
+    fn copy(&self) -> Result<Self> {
+        Ok(Self {
+            subdivisions: self.subdivisions.copy()?,
+            durations: self.durations.copy()?,
+            duration: self.duration,
+            remaining: self.remaining,
+        })
+    }
+}
@@ -0,0 +1,182 @@
+//! The patterns, which make rhythms.
+use std::collections::VecDeque;
+
+use koto::{prelude::*, runtime::Result};
+
+use super::{arg, knumber, make_pattern, number, or, Generator, Source};
+
+pub(super) fn peuclid(ctx: &mut CallContext) -> Result<KValue> {
+    let args = ctx.args();
+    let dur = match arg(args, 4) {
+        KValue::Null => None,
+        dur => Some(Source::new(dur)),
+    };
+
+    Ok(make_pattern(Euclid {
+        hits: Source::new(arg(args, 0)),
+        steps: Source::new(arg(args, 1)),
+        rotation: Source::or(arg(args, 2), 0),
+        repeats: number(&or(arg(args, 3), 1), "peuclid")?,
+        dur,
+        values: VecDeque::new(),
+    }))
+}
+
+// the rhythm of a cycle is built at once, so it's bounded
+const MAX_STEPS: f64 = 1024.0;
+
+/// Yields the steps of the Euclidean rhythm as `1` or `"rest"`, or the durations between the
+/// hits, if the step duration is given. The arguments are pulled once per cycle.
+struct Euclid {
+    hits: Source,
+    steps: Source,
+    rotation: Source,
+    repeats: f64,
+    dur: Option<Source>,
+    // the rest of the current cycle
+    values: VecDeque<KValue>,
+}
+
+impl Generator for Euclid {
+    fn generate(&mut self) -> Result<Option<KValue>> {
+        loop {
+            if let Some(value) = self.values.pop_front() {
+                return Ok(Some(value));
+            }
+            if self.repeats <= 0.0 {
+                return Ok(None);
+            }
+
+            let (Some(hits), Some(steps), Some(rotation)) = (
+                self.hits.next_number("peuclid")?,
+                self.steps.next_number("peuclid")?,
+                self.rotation.next_number("peuclid")?,
+            ) else {
+                return Ok(None);
+            };
+            // NaN is rejected too
+            if !(1.0..=MAX_STEPS).contains(&steps) {
+                return runtime_error!(
+                    "kotoist.peuclid: expected from 1 to {} steps, but got {}",
+                    MAX_STEPS,
+                    steps
+                );
+            }
+            let rhythm = bjorklund(hits, steps, rotation);
+
+            match &mut self.dur {
+                None => self.values.extend(rhythm.iter().map(|&is_hit| {
+                    if is_hit {
+                        KNumber::I64(1).into()
+                    } else {
+                        KValue::Str("rest".into())
+                    }
+                })),
+                Some(dur) => {
+                    let dur = match dur.try_next()? {
+                        KValue::Null => return Ok(None),
+                        dur => knumber(&dur, "peuclid")?,
+                    };
+                    let onsets: Vec<usize> = (0..rhythm.len()).filter(|&i| rhythm[i]).collect();
+                    if onsets.is_empty() {
+                        return runtime_error!(
+                            "kotoist.peuclid: no hits to yield the durations of"
+                        );
+                    }
+
+                    // the last hit lasts until the first one of the next cycle
+                    let ends = onsets.iter().skip(1).copied();
+                    let ends = ends.chain(std::iter::once(onsets[0] + rhythm.len()));
+                    self.values.extend(
+                        onsets
+                            .iter()
+                            .zip(ends)
+                            .map(|(onset, end)| (KNumber::I64((end - onset) as i64) * dur).into()),
+                    );
+                }
+            }
+            self.repeats -= 1.0;
+        }
+    }
+
+    fn copy(&self) -> Result<Self> {
+        Ok(Self {
+            hits: self.hits.copy()?,
+            steps: self.steps.copy()?,
+            rotation: self.rotation.copy()?,
+            repeats: self.repeats,
+            dur: self.dur.as_ref().map(Source::copy).transpose()?,
+            values: self.values.clone(),
+        })
+    }
+}
+
+/// The hits spread over the steps as evenly as possible by Bjorklund's algorithm, rotated to the
+/// left by the rotation.
+fn bjorklund(hits: f64, steps: f64, rotation: f64) -> Vec<bool> {
+    let steps = steps.floor() as usize;
+    let hits = (hits.floor().max(0.0) as usize).min(steps);
+
+    // the groups of the remainder are appended to the leading groups, until one is left
+    let mut leading = vec![vec![true]; hits];
+    let mut remainder = vec![vec![false]; steps - hits];
+    while remainder.len() > 1 && !leading.is_empty() {
+        let n = leading.len().min(remainder.len());
+        let rest = if leading.len() > n {
+            leading.split_off(n)
+        } else {
+            remainder.split_off(n)
+        };
+        for (group, tail) in leading.iter_mut().zip(remainder.drain(..n)) {
+            group.extend(tail);
+        }
+        remainder = rest;
+    }
+
+    let mut rhythm: Vec<bool> = leading.into_iter().chain(remainder).flatten().collect();
+    rhythm.rotate_left((rotation.floor() as i64).rem_euclid(steps as i64) as usize);
+    rhythm
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rhythm(hits: f64, steps: f64, rotation: f64) -> String {
+        bjorklund(hits, steps, rotation)
+            .iter()
+            .map(|&is_hit| if is_hit { 'x' } else { '.' })
+            .collect()
+    }
+
+    #[test]
+    fn test_bjorklund() {
+        assert_eq!(rhythm(3.0, 8.0, 0.0), "x..x..x.");
+        assert_eq!(rhythm(5.0, 8.0, 0.0), "x.xx.xx.");
+        assert_eq!(rhythm(2.0, 5.0, 0.0), "x.x..");
+        assert_eq!(rhythm(4.0, 12.0, 0.0), "x..x..x..x..");
+        assert_eq!(rhythm(7.0, 16.0, 0.0), "x..x.x.x..x.x.x.");
+        assert_eq!(rhythm(3.0, 8.0, 1.0), "..x..x.x");
+        assert_eq!(rhythm(3.0, 8.0, -1.0), ".x..x..x");
+        assert_eq!(rhythm(0.0, 4.0, 0.0), "....");
+        assert_eq!(rhythm(6.0, 4.0, 0.0), "xxxx");
+    }
+
+    #[test]
+    fn test_steps_range() {
+        let euclid = |steps: f64| Euclid {
+            hits: Source::new(3.into()),
+            steps: Source::new(steps.into()),
+            rotation: Source::new(0.into()),
+            repeats: 1.0,
+            dur: None,
+            values: VecDeque::new(),
+        };
+
+        assert!(euclid(8.0).generate().is_ok());
+        assert!(euclid(MAX_STEPS).generate().is_ok());
+        for steps in [0.5, f64::NAN, MAX_STEPS + 1.0, f64::INFINITY] {
+            assert!(euclid(steps).generate().is_err(), "{} steps", steps);
+        }
+    }
+}
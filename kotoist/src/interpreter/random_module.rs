@@ -0,0 +1,72 @@
+//! The `random` module of the scripts.
+//!
+//! While a player pulls its pattern, the random numbers are drawn from the generator of the
+//! player, so the generators written in Koto follow its seed as the native patterns do. Otherwise
+//! they're drawn from the `random` module of the core library, which is seeded with the global
+//! seed, when the project is loaded and when the seed is set.
+use koto::{prelude::*, runtime::Result};
+use koto_random::make_module as make_core_module;
+
+use crate::orchestrator::rng;
+
+pub(crate) fn make_module() -> KMap {
+    let module = make_core_module();
+
+    if let Some(number) = module.get("number") {
+        module.add_fn("number", move |ctx| match rng::number() {
+            Some(number) => Ok(number.into()),
+            None => ctx.vm.call_function(number.clone(), &[]),
+        });
+    }
+
+    if let Some(boolean) = module.get("bool") {
+        module.add_fn("bool", move |ctx| match rng::number() {
+            Some(number) => Ok((number < 0.5).into()),
+            None => ctx.vm.call_function(boolean.clone(), &[]),
+        });
+    }
+
+    if let Some(function) = module.get("pick") {
+        module.add_fn("pick", move |ctx| {
+            if let [container] = ctx.args() {
+                if let Some(item) = pick(container) {
+                    return Ok(item);
+                }
+            }
+            let args = ctx.args().to_vec();
+            ctx.vm.call_function(function.clone(), args.as_slice())
+        });
+    }
+
+    module
+}
+
+/// The random item of the list or the tuple drawn from the generator of the player. `None`, if
+/// there's no current generator, the container is empty or it's of another type.
+pub(super) fn pick(container: &KValue) -> Option<KValue> {
+    fn pick_from(items: &[KValue]) -> Option<KValue> {
+        if items.is_empty() {
+            return None;
+        }
+        rng::index(items.len()).map(|index| items[index].clone())
+    }
+
+    match container {
+        KValue::List(list) => pick_from(&list.data()),
+        KValue::Tuple(tuple) => pick_from(tuple),
+        _ => None,
+    }
+}
+
+/// Seeds the `random` module of the prelude, so the numbers drawn outside of the players repeat.
+pub(super) fn seed(vm: &mut KotoVm, seed: u64) -> Result<()> {
+    let function = match vm.prelude().get("random") {
+        Some(KValue::Map(module)) => module.get("seed"),
+        _ => None,
+    };
+    if let Some(function) = function {
+        vm.call_function(function, &[(seed as i64).into()])?;
+    }
+
+    Ok(())
+}
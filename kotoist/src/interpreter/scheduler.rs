@@ -0,0 +1,299 @@
+//! Koto functions and routines scheduled at beat positions. The scheduler is driven by the clock
+//! messages of the interpreter worker, so it's as precise as the MIDI clock (24 PPQ).
+use koto::prelude::*;
+
+pub(crate) type TaskId = u64;
+
+#[derive(Default)]
+pub(crate) struct Scheduler {
+    tasks: Vec<Task>,
+    next_id: TaskId,
+    // the latest beat position of the transport, reported by the clock or passed to the tasks
+    // scheduled relative to it
+    position: f64,
+}
+
+struct Task {
+    id: TaskId,
+    due: f64,
+    kind: TaskKind,
+}
+
+enum TaskKind {
+    Function {
+        function: KValue,
+        // the repeating tasks are rescheduled after the interval
+        interval: Option<f64>,
+    },
+    /// The generator, which yields the number of beats to wait before it's resumed.
+    Routine(KIterator),
+}
+
+/// The task, which should be run now.
+pub(crate) struct DueTask {
+    pub(crate) id: TaskId,
+    pub(crate) due: f64,
+    pub(crate) action: DueAction,
+}
+
+pub(crate) enum DueAction {
+    Call {
+        function: KValue,
+        is_repeating: bool,
+    },
+    /// The routine is suspended until it's resumed with `Scheduler::resume`.
+    Resume(KIterator),
+}
+
+impl Scheduler {
+    /// Schedules the function at the absolute beat position.
+    pub(crate) fn at(&mut self, beat: f64, function: KValue) -> TaskId {
+        self.push(
+            beat,
+            TaskKind::Function {
+                function,
+                interval: None,
+            },
+        )
+    }
+
+    /// Schedules the function to be called every `interval` beats, starting `interval` beats after
+    /// the current beat position of the transport.
+    pub(crate) fn sched(&mut self, position: f64, interval: f64, function: KValue) -> TaskId {
+        self.position = position;
+        self.push(
+            position + interval,
+            TaskKind::Function {
+                function,
+                interval: Some(interval),
+            },
+        )
+    }
+
+    /// Schedules the routine to start at the next clock tick from the current beat position of the
+    /// transport.
+    pub(crate) fn routine(&mut self, position: f64, iterator: KIterator) -> TaskId {
+        self.position = position;
+        self.push(position, TaskKind::Routine(iterator))
+    }
+
+    /// Resumes the routine at the beat position. Returns `false` if it's cancelled.
+    pub(crate) fn resume(&mut self, id: TaskId, due: f64) -> bool {
+        match self.tasks.iter_mut().find(|task| task.id == id) {
+            Some(task) => {
+                task.due = due;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Moves the repeating tasks and the waiting routines by the jump of the transport to the beat
+    /// position (e.g. on the loop wrap or the locate), so they keep their distance from the
+    /// position. The tasks at the absolute positions stay.
+    pub(crate) fn jump(&mut self, position: f64) {
+        let distance = position - self.position;
+        self.position = position;
+
+        for task in &mut self.tasks {
+            match task.kind {
+                TaskKind::Function {
+                    interval: Some(_), ..
+                }
+                | TaskKind::Routine(_) => task.due += distance,
+                TaskKind::Function { interval: None, .. } => {}
+            }
+        }
+    }
+
+    /// Returns `false` if there's no such task (e.g. it's already done).
+    pub(crate) fn cancel(&mut self, id: TaskId) -> bool {
+        let count = self.tasks.len();
+        self.tasks.retain(|task| task.id != id);
+        self.tasks.len() != count
+    }
+
+    /// Takes the tasks due at the position in the order of their positions. The repeating tasks
+    /// stay scheduled, and the routines stay suspended.
+    pub(crate) fn take_due(&mut self, position: f64) -> Vec<DueTask> {
+        self.position = position;
+
+        let mut due = Vec::new();
+        self.tasks.retain_mut(|task| {
+            // the clock messages are sent at the ticks, so the position can be slightly passed
+            if task.due > position + 1e-6 {
+                return true;
+            }
+
+            let action = match &task.kind {
+                TaskKind::Function { function, interval } => DueAction::Call {
+                    function: function.clone(),
+                    is_repeating: interval.is_some(),
+                },
+                TaskKind::Routine(iterator) => DueAction::Resume(iterator.clone()),
+            };
+            due.push(DueTask {
+                id: task.id,
+                due: task.due,
+                action,
+            });
+
+            match task.kind {
+                TaskKind::Function {
+                    interval: Some(interval),
+                    ..
+                } => {
+                    // the missed calls (e.g. after a jump) are skipped
+                    while task.due <= position + 1e-6 {
+                        task.due += interval;
+                    }
+                    true
+                }
+                TaskKind::Function { interval: None, .. } => false,
+                TaskKind::Routine(_) => {
+                    task.due = f64::INFINITY;
+                    true
+                }
+            }
+        });
+        due.sort_by(|a, b| a.due.total_cmp(&b.due));
+
+        due
+    }
+
+    fn push(&mut self, due: f64, kind: TaskKind) -> TaskId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.tasks.push(Task { id, due, kind });
+
+        id
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn due_ids(scheduler: &mut Scheduler, position: f64) -> Vec<TaskId> {
+        scheduler
+            .take_due(position)
+            .into_iter()
+            .map(|task| task.id)
+            .collect()
+    }
+
+    #[test]
+    fn test_scheduler() {
+        let mut scheduler = Scheduler::default();
+        assert!(due_ids(&mut scheduler, 2.0).is_empty());
+
+        let once = scheduler.at(4.0, KValue::Null);
+        let repeating = scheduler.sched(2.0, 1.5, KValue::Null);
+        let cancelled = scheduler.at(3.0, KValue::Null);
+        assert!(scheduler.cancel(cancelled));
+        assert!(!scheduler.cancel(cancelled));
+
+        assert!(due_ids(&mut scheduler, 3.4).is_empty());
+        assert_eq!(due_ids(&mut scheduler, 3.5), [repeating]);
+        // sorted by the position
+        assert_eq!(due_ids(&mut scheduler, 5.0), [once, repeating]);
+        // the missed calls are skipped
+        assert_eq!(due_ids(&mut scheduler, 9.0), [repeating]);
+        assert!(due_ids(&mut scheduler, 9.4).is_empty());
+        assert_eq!(due_ids(&mut scheduler, 9.5), [repeating]);
+
+        assert!(scheduler.cancel(repeating));
+        assert!(due_ids(&mut scheduler, 20.0).is_empty());
+    }
+
+    #[test]
+    fn test_jump() {
+        let mut scheduler = Scheduler::default();
+        due_ids(&mut scheduler, 16.0);
+
+        let once = scheduler.at(18.0, KValue::Null);
+        let repeating = scheduler.sched(16.0, 1.0, KValue::Null);
+        assert_eq!(due_ids(&mut scheduler, 17.0), [repeating]);
+
+        // the loop wraps back, the repeating task keeps running from there
+        scheduler.jump(0.5);
+        assert!(due_ids(&mut scheduler, 1.4).is_empty());
+        assert_eq!(due_ids(&mut scheduler, 1.5), [repeating]);
+        assert_eq!(due_ids(&mut scheduler, 2.5), [repeating]);
+
+        // the locate forward doesn't make it due right away
+        scheduler.jump(17.9);
+        assert_eq!(due_ids(&mut scheduler, 18.0), [once]);
+        assert!(due_ids(&mut scheduler, 18.8).is_empty());
+        assert_eq!(due_ids(&mut scheduler, 18.9), [repeating]);
+    }
+
+    #[test]
+    fn test_routine() {
+        let mut scheduler = Scheduler::default();
+        due_ids(&mut scheduler, 2.0);
+
+        let routine = scheduler.routine(
+            2.0,
+            KIterator::with_std_iter(std::iter::empty::<KIteratorOutput>()),
+        );
+        assert_eq!(due_ids(&mut scheduler, 2.0), [routine]);
+        // suspended until resumed
+        assert!(due_ids(&mut scheduler, 100.0).is_empty());
+        assert!(scheduler.resume(routine, 101.0));
+        assert!(due_ids(&mut scheduler, 100.5).is_empty());
+        assert_eq!(due_ids(&mut scheduler, 101.0), [routine]);
+
+        assert!(scheduler.cancel(routine));
+        assert!(!scheduler.resume(routine, 102.0));
+    }
+
+    #[test]
+    fn test_routine_jump() {
+        let mut scheduler = Scheduler::default();
+        due_ids(&mut scheduler, 16.0);
+
+        let routine = scheduler.routine(
+            16.0,
+            KIterator::with_std_iter(std::iter::empty::<KIteratorOutput>()),
+        );
+        let repeating = scheduler.sched(16.0, 4.0, KValue::Null);
+        assert_eq!(due_ids(&mut scheduler, 16.0), [routine]);
+        assert!(scheduler.resume(routine, 18.0));
+
+        // the loop wraps back, the routine and the repeating task wait as long as before
+        scheduler.jump(0.0);
+        assert!(due_ids(&mut scheduler, 1.9).is_empty());
+        assert_eq!(due_ids(&mut scheduler, 2.0), [routine]);
+        assert_eq!(due_ids(&mut scheduler, 4.0), [repeating]);
+        assert!(scheduler.cancel(repeating));
+
+        // the suspended routine isn't resumed by the jump
+        scheduler.jump(32.0);
+        assert!(due_ids(&mut scheduler, 100.0).is_empty());
+        assert!(scheduler.resume(routine, 101.0));
+        assert_eq!(due_ids(&mut scheduler, 101.0), [routine]);
+    }
+
+    #[test]
+    fn test_sched_position() {
+        // scheduled before the transport plays, there's no clock tick yet
+        let mut scheduler = Scheduler::default();
+        let repeating = scheduler.sched(32.0, 4.0, KValue::Null);
+        let routine = scheduler.routine(
+            32.0,
+            KIterator::with_std_iter(std::iter::empty::<KIteratorOutput>()),
+        );
+
+        // the transport starts from where it's stopped, nothing is due in a burst
+        scheduler.jump(32.0);
+        assert_eq!(due_ids(&mut scheduler, 32.0), [routine]);
+        assert!(due_ids(&mut scheduler, 35.9).is_empty());
+        assert_eq!(due_ids(&mut scheduler, 36.0), [repeating]);
+
+        // scheduled between the ticks, it's counted from the current beat
+        let later = scheduler.sched(37.5, 1.0, KValue::Null);
+        assert!(due_ids(&mut scheduler, 38.4).is_empty());
+        assert_eq!(due_ids(&mut scheduler, 38.5), [later]);
+    }
+}
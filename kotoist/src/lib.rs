@@ -15,36 +15,81 @@ use std::sync::{mpsc, Arc, Mutex};
 
 use nih_plug::prelude::*;
 
+use crate::clock::ClockPosition;
 use crate::editor::{create_editor, PianoRollEvent};
+use crate::midi::{MidiMessage, MidiOut, NoteOff, NoteOffs};
 use crate::orchestrator::{Event, EventValue};
 use crate::parameters::{InterpreterMessage, Parameters};
 
+pub use crate::midi::SysEx;
+
+mod automation;
+mod clock;
 mod editor;
 mod interpreter;
+mod midi;
 mod orchestrator;
 mod parameters;
 mod pipe;
+mod scene;
+mod status;
 
 const NUM_CHANNELS: u32 = 2;
+// the MIDI clock ticks per quarter note
+const CLOCK_PPQ: f64 = 24.0;
 
 /// Plugin entry-point.
 pub struct Kotoist {
     params: Arc<Parameters>,
     editor: Option<Box<dyn Editor>>,
+    clock_position: ClockPosition,
+    // the bar and beat, at which the last on_bar/on_beat callbacks were sent
+    last_bar_and_beat: Option<(i64, u32)>,
+    // the MIDI clock tick, at which the last on_clock callback was sent
+    last_clock_tick: Option<i64>,
+    // the value of the scene parameter in the previous block
+    last_scene: Option<i32>,
+    // the tempo in the previous block
+    last_tempo: Option<f64>,
+    // the position in samples and beats, at which the next block should start, if there's no jump
+    next_block_position: Option<(f64, f64)>,
+    // whether the transport has played since the last stop, so the next locate, when it's paused,
+    // is the stop
+    played_since_stop: bool,
+    midi_out: MidiOut,
+    // the note-offs of the notes sent by the `note` function
+    immediate_note_offs: NoteOffs,
+    // the number of samples processed since the start, it's used to schedule the immediate
+    // note-offs regardless of the transport state
+    elapsed_samples: u64,
 }
 
 impl Default for Kotoist {
     fn default() -> Self {
         let (pipe_in, pipe_out) = pipe::new_pipe();
         let (piano_roll_sender, piano_roll_receiver) = mpsc::channel();
-        let params = Arc::new(Parameters::new(pipe_in, piano_roll_sender));
+        let (midi_in, midi_out) = midi::new_midi_queue();
+        let params = Arc::new(Parameters::new(pipe_in, piano_roll_sender, midi_in));
         let editor = create_editor(
             params.clone(),
             Arc::new(Mutex::new(pipe_out)),
             piano_roll_receiver,
         );
 
-        Self { params, editor }
+        Self {
+            params,
+            editor,
+            clock_position: ClockPosition::default(),
+            last_bar_and_beat: None,
+            last_clock_tick: None,
+            last_scene: None,
+            last_tempo: None,
+            next_block_position: None,
+            played_since_stop: false,
+            midi_out,
+            immediate_note_offs: NoteOffs::default(),
+            elapsed_samples: 0,
+        }
     }
 }
 
@@ -64,7 +109,7 @@ impl Plugin for Kotoist {
     const MIDI_OUTPUT: MidiConfig = MidiConfig::MidiCCs;
     const SAMPLE_ACCURATE_AUTOMATION: bool = true;
     type BackgroundTask = ();
-    type SysExMessage = ();
+    type SysExMessage = SysEx;
 
     fn initialize(
         &mut self,
@@ -72,6 +117,8 @@ impl Plugin for Kotoist {
         _buffer_config: &BufferConfig,
         _context: &mut impl InitContext<Self>,
     ) -> bool {
+        self.params
+            .send_interpreter_msg(InterpreterMessage::SeedRandom);
         self.params.eval_all_right_to_left();
         self.params.send_interpreter_msg(InterpreterMessage::OnLoad);
         true
@@ -96,131 +143,451 @@ impl Plugin for Kotoist {
     ) -> ProcessStatus {
         self.params.check_gl_context_valid();
 
-        if let Ok(mut orch) = self.params.orchestrator.try_lock() {
-            self.process_incoming_events(context, buffer.samples());
-
-            let transport = orchestrator::Transport::from(context.transport());
-
-            // send generated events
-            for frame_offset in 0..buffer.samples() {
-                // update beat position in params
-                let beats_position = context
-                    .transport()
-                    .pos_beats()
-                    .expect("This plugin can't work without beat position");
+        let BlockTransport {
+            transport,
+            beats_position,
+            tempo,
+            is_playing,
+            is_internal,
+        } = self.block_transport(context);
+
+        self.send_immediate_events(context, transport.beat_length);
+        // the jump goes before the clock messages of the block, so the scheduled tasks are moved
+        // before they're run
+        self.send_transport_changes(&transport, beats_position, tempo, is_playing);
+        self.params
+            .playback
+            .set_transport(tempo, is_playing, transport.time_signature);
+
+        for (index, value) in self.params.macro_values.take_changed() {
+            self.params
+                .send_interpreter_msg(InterpreterMessage::OnMacro(index, value));
+        }
 
-                let tempo = context.transport().tempo.unwrap_or(120.0);
-                let is_playing = context.transport().playing;
+        let scene = self.params.scene.value();
+        if self
+            .last_scene
+            .is_some_and(|last_scene| last_scene != scene)
+        {
+            self.params
+                .send_interpreter_msg(InterpreterMessage::OnScene(scene));
+        }
+        self.last_scene = Some(scene);
+
+        let beats_per_sample = tempo / 60.0 / context.transport().sample_rate as f64;
+        let modifiers = self.params.modifiers();
+        // the guard borrows the cloned parameters, so the methods of `self` can be called
+        let params = self.params.clone();
+        let mut orch = params.orchestrator.try_lock().ok();
+
+        if orch.is_some() {
+            if is_playing {
+                self.params
+                    .send_interpreter_msg(InterpreterMessage::OnPlay(beats_position, tempo));
+            } else {
+                self.params
+                    .send_interpreter_msg(InterpreterMessage::OnPause(beats_position, tempo));
+            }
+        }
 
-                if is_playing {
-                    self.params
-                        .send_interpreter_msg(InterpreterMessage::OnPlay(beats_position, tempo));
-                } else {
-                    self.params
-                        .send_interpreter_msg(InterpreterMessage::OnPause(beats_position, tempo));
+        // the events are sent sample by sample, so they go out in the order of their timing
+        let mut next_event = context.next_event();
+        for frame_offset in 0..buffer.samples() {
+            // the incoming events are processed regardless of the orchestrator state, so the MIDI
+            // thru doesn't drop them
+            next_event = self.process_incoming_events(
+                context,
+                next_event,
+                frame_offset,
+                beats_position,
+                beats_per_sample,
+            );
+            self.send_immediate_note_offs(context, frame_offset);
+
+            let Some(orch) = orch.as_mut() else {
+                continue;
+            };
+
+            // calculate beats offset
+            let beats_per_second = tempo / 60.0;
+            let beats_per_sample = beats_per_second as f32 / context.transport().sample_rate;
+            let beats_offset = beats_per_sample * frame_offset as f32;
+            let beats_position = beats_position as f32 + beats_offset;
+
+            // update beat position in params
+            self.params.on_beats_position_changed(beats_position);
+
+            let bar_and_beat = transport.bar_and_beat(transport.position + frame_offset as f64);
+            if is_playing && self.last_bar_and_beat != Some(bar_and_beat) {
+                let (bar, beat) = bar_and_beat;
+                let beats_position = beats_position as f64;
+                if self.last_bar_and_beat.map(|(last_bar, _)| last_bar) != Some(bar) {
+                    // the bar grid in beats for the scenes
+                    let bar_length = transport.bar_length();
+                    let bar_start = transport.bar_origin() + bar as f64 * bar_length;
+                    let position = transport.position + frame_offset as f64;
+                    self.params.send_interpreter_msg(InterpreterMessage::OnBar(
+                        bar,
+                        beats_position,
+                        beats_position + (bar_start - position) / transport.beat_length,
+                        bar_length / transport.beat_length,
+                    ));
                 }
-                // calculate beats offset
-                let beats_per_second = context
-                    .transport()
-                    .tempo
-                    .map(|bpm| bpm / 60.0)
-                    .unwrap_or_default() as f32;
-                let beats_per_sample = beats_per_second / context.transport().sample_rate;
-                let beats_offset = beats_per_sample * frame_offset as f32;
-                let beats_position = beats_position as f32 + beats_offset;
-
-                self.params.on_beats_position_changed(beats_position);
-
-                let piano_roll_events: Vec<PianoRollEvent> = orch
-                    .tick(is_playing, &transport, frame_offset)
-                    .iter()
-                    .flat_map(plugin_note_from_event)
-                    .filter_map(|e| {
-                        context.send_event(e);
-
-                        match e {
-                            PluginNoteEvent::<Self>::NoteOn { channel, note, .. } => {
-                                Some(PianoRollEvent {
-                                    channel,
-                                    pitch: note % 36,
-                                    position_in_beats: beats_position,
-                                    is_on: true,
-                                })
-                            }
-                            PluginNoteEvent::<Self>::NoteOff { channel, note, .. } => {
-                                Some(PianoRollEvent {
-                                    channel,
-                                    pitch: note % 36,
-                                    position_in_beats: beats_position,
-                                    is_on: false,
-                                })
-                            }
-
-                            _ => None,
+                self.params.send_interpreter_msg(InterpreterMessage::OnBeat(
+                    beat,
+                    bar,
+                    beats_position,
+                ));
+            }
+            self.last_bar_and_beat = Some(bar_and_beat);
+
+            let clock_tick = (beats_position as f64 * CLOCK_PPQ).floor() as i64;
+            if is_playing && self.last_clock_tick != Some(clock_tick) {
+                self.params
+                    .send_interpreter_msg(InterpreterMessage::OnClock(
+                        clock_tick,
+                        beats_position as f64,
+                    ));
+            }
+            self.last_clock_tick = Some(clock_tick);
+
+            let piano_roll_events: Vec<PianoRollEvent> = orch
+                .tick(is_playing, &transport, frame_offset, &modifiers)
+                .iter()
+                .flat_map(plugin_note_from_event)
+                .filter_map(|e| {
+                    let piano_roll_event = match &e {
+                        PluginNoteEvent::<Self>::NoteOn { channel, note, .. } => {
+                            Some(PianoRollEvent {
+                                channel: *channel,
+                                pitch: note % 36,
+                                position_in_beats: beats_position,
+                                is_on: true,
+                            })
+                        }
+                        PluginNoteEvent::<Self>::NoteOff { channel, note, .. } => {
+                            Some(PianoRollEvent {
+                                channel: *channel,
+                                pitch: note % 36,
+                                position_in_beats: beats_position,
+                                is_on: false,
+                            })
                         }
-                    })
-                    .collect();
 
-                if !piano_roll_events.is_empty() {
-                    self.params.send_piano_roll_events(piano_roll_events);
-                }
+                        _ => None,
+                    };
+
+                    context.send_event(e);
+
+                    piano_roll_event
+                })
+                .collect();
+
+            if !piano_roll_events.is_empty() {
+                self.params.send_piano_roll_events(piano_roll_events);
             }
         }
 
+        if let Some(orch) = orch {
+            orch.update_players_status();
+        }
+        self.elapsed_samples += buffer.samples() as u64;
+
+        self.next_block_position = Some(if is_playing {
+            let block_size = buffer.samples() as f64;
+            (
+                transport.position + block_size,
+                beats_position + block_size * beats_per_sample,
+            )
+        } else {
+            (transport.position, beats_position)
+        });
+
+        // the internal clock stands still while the host drives the transport
+        if is_internal {
+            self.clock_position.advance(
+                &self.params.clock,
+                buffer.samples(),
+                context.transport().sample_rate,
+            );
+        }
+
         ProcessStatus::KeepAlive
     }
 }
 
 impl Kotoist {
-    fn process_incoming_events(&self, context: &mut impl ProcessContext<Self>, block_size: usize) {
-        let mut next_event = context.next_event();
-        for s in 0..block_size {
-            // don't context.next_event(), but trying to handle the same event until timing match
-            while let Some(event) = next_event {
-                if event.timing() != s as u32 {
-                    break;
+    // Get the transport state from the host or, if the host doesn't provide the beat position (or
+    // there's no host), from the internal clock.
+    fn block_transport(&mut self, context: &mut impl ProcessContext<Self>) -> BlockTransport {
+        let host = context.transport();
+
+        match host.pos_beats() {
+            Some(beats_position) if !matches!(context.plugin_api(), PluginApi::Standalone) => {
+                BlockTransport {
+                    transport: orchestrator::Transport::from(host),
+                    beats_position,
+                    tempo: host.tempo.unwrap_or(120.0),
+                    is_playing: host.playing,
+                    is_internal: false,
+                }
+            }
+            _ => {
+                let clock = &self.params.clock;
+                self.clock_position.sync(clock);
+
+                BlockTransport {
+                    transport: self.clock_position.transport(clock, host.sample_rate),
+                    beats_position: self.clock_position.beats(),
+                    tempo: clock.tempo() as f64,
+                    is_playing: clock.is_playing(),
+                    is_internal: true,
                 }
+            }
+        }
+    }
 
-                match event {
-                    NoteEvent::NoteOn {
+    // Send the callbacks on the tempo change and on the position jump. The first jump, after the
+    // transport has stopped playing, is the stop (i.e. it's paused and rewound), the further ones
+    // (while it's still paused) aren't reported.
+    fn send_transport_changes(
+        &mut self,
+        transport: &orchestrator::Transport,
+        beats_position: f64,
+        tempo: f64,
+        is_playing: bool,
+    ) {
+        if let Some(last_tempo) = self.last_tempo.filter(|last| (last - tempo).abs() > 1e-6) {
+            self.params
+                .send_interpreter_msg(InterpreterMessage::OnTempoChange(last_tempo, tempo));
+        }
+        self.last_tempo = Some(tempo);
+
+        if let Some((position, beats)) = self.next_block_position {
+            if orchestrator::is_jump(position, transport.position) {
+                if is_playing {
+                    self.params
+                        .send_interpreter_msg(InterpreterMessage::OnJump(beats, beats_position));
+                } else if self.played_since_stop {
+                    self.played_since_stop = false;
+                    self.params
+                        .send_interpreter_msg(InterpreterMessage::OnStop(beats_position));
+                }
+            }
+        }
+        if is_playing {
+            self.played_since_stop = true;
+        }
+    }
+
+    // Send the events from the immediate MIDI functions (`note_on`, `cc`, etc.) at the beginning of
+    // the block. The note-offs due by then go first, so a note retriggered by `note` isn't cut.
+    fn send_immediate_events(&mut self, context: &mut impl ProcessContext<Self>, beat_length: f64) {
+        self.send_immediate_note_offs(context, 0);
+
+        for message in self.midi_out.pending() {
+            match message {
+                MidiMessage::NoteOn {
+                    note,
+                    velocity,
+                    channel,
+                } => context.send_event(NoteEvent::NoteOn {
+                    timing: 0,
+                    voice_id: None,
+                    channel,
+                    note,
+                    velocity,
+                }),
+                MidiMessage::NoteOff { note, channel } => context.send_event(NoteEvent::NoteOff {
+                    timing: 0,
+                    voice_id: None,
+                    channel,
+                    note,
+                    velocity: 0.0,
+                }),
+                MidiMessage::Note {
+                    note,
+                    velocity,
+                    channel,
+                    dur,
+                } => {
+                    context.send_event(NoteEvent::NoteOn {
+                        timing: 0,
+                        voice_id: None,
                         channel,
                         note,
                         velocity,
-                        ..
-                    }
-                    | NoteEvent::NoteOff {
-                        channel,
+                    });
+                    let note_off = NoteOff {
+                        position: self.elapsed_samples + (dur * beat_length) as u64,
                         note,
-                        velocity,
-                        ..
-                    } => {
-                        let velocity = if matches!(event, NoteEvent::NoteOff { .. }) {
-                            0.0
-                        } else {
-                            velocity
-                        };
-                        self.params
-                            .send_interpreter_msg(InterpreterMessage::OnMidiIn(
-                                note, velocity, channel,
-                            ));
-                    }
-                    NoteEvent::MidiCC {
-                        channel, cc, value, ..
-                    } => {
-                        self.params
-                            .send_interpreter_msg(InterpreterMessage::OnMidiInCc(
-                                cc, value, channel,
-                            ));
+                        channel,
+                    };
+                    // there's no room to wait, the earliest note is released right away
+                    if let Some(evicted) = self.immediate_note_offs.insert(note_off) {
+                        context.send_event(note_off_event(&evicted, 0));
                     }
-                    _ => (),
                 }
+                MidiMessage::Cc { cc, value, channel } => context.send_event(NoteEvent::MidiCC {
+                    timing: 0,
+                    channel,
+                    cc,
+                    value,
+                }),
+                MidiMessage::SysEx(message) => {
+                    context.send_event(NoteEvent::MidiSysEx { timing: 0, message })
+                }
+            }
+        }
+    }
+
+    // Send the note-offs of the `note` function, which are due at the sample of the block.
+    fn send_immediate_note_offs(
+        &mut self,
+        context: &mut impl ProcessContext<Self>,
+        frame_offset: usize,
+    ) {
+        let position = self.elapsed_samples + frame_offset as u64;
+        while let Some(note_off) = self.immediate_note_offs.pop_due(position) {
+            context.send_event(note_off_event(&note_off, frame_offset as u32));
+        }
+    }
+
+    // Handle the incoming events at the sample of the block. Returns the first event of the later
+    // samples, which is taken from the context, but not handled yet.
+    fn process_incoming_events(
+        &self,
+        context: &mut impl ProcessContext<Self>,
+        mut next_event: Option<PluginNoteEvent<Self>>,
+        s: usize,
+        beats_position: f64,
+        beats_per_sample: f64,
+    ) -> Option<PluginNoteEvent<Self>> {
+        // don't context.next_event(), but trying to handle the same event until timing match
+        while let Some(event) = next_event.take() {
+            if event.timing() != s as u32 {
+                return Some(event);
+            }
 
-                next_event = context.next_event();
+            if let Some(channel) = event.channel() {
+                if matches!(
+                    event,
+                    NoteEvent::NoteOn { .. } | NoteEvent::NoteOff { .. } | NoteEvent::MidiCC { .. }
+                ) && self.params.midi_state.thru.passes(channel)
+                {
+                    context.send_event(event.clone());
+                }
             }
+
+            match event {
+                NoteEvent::NoteOn {
+                    channel,
+                    note,
+                    velocity,
+                    ..
+                }
+                | NoteEvent::NoteOff {
+                    channel,
+                    note,
+                    velocity,
+                    ..
+                } => {
+                    let velocity = if matches!(event, NoteEvent::NoteOff { .. }) {
+                        0.0
+                    } else {
+                        velocity
+                    };
+                    if velocity > 0.0 {
+                        self.params.midi_state.held_notes.press(note);
+                    } else {
+                        self.params.midi_state.held_notes.release(note);
+                    }
+                    self.params
+                        .send_interpreter_msg(InterpreterMessage::OnMidiIn(
+                            note,
+                            velocity,
+                            channel,
+                            beats_position + s as f64 * beats_per_sample,
+                        ));
+                }
+                NoteEvent::MidiCC {
+                    channel, cc, value, ..
+                } => {
+                    self.params.midi_state.cc.set(cc, channel, value);
+                    self.params
+                        .send_interpreter_msg(InterpreterMessage::OnMidiInCc(cc, value, channel));
+                }
+                NoteEvent::MidiPitchBend { channel, value, .. } => {
+                    // from 0.0..1.0 with the center at 0.5 to -1.0..1.0
+                    self.params
+                        .send_interpreter_msg(InterpreterMessage::OnPitchBend(
+                            value * 2.0 - 1.0,
+                            channel,
+                        ));
+                }
+                NoteEvent::MidiChannelPressure {
+                    channel, pressure, ..
+                } => {
+                    self.params
+                        .send_interpreter_msg(InterpreterMessage::OnAftertouch(
+                            pressure, channel, None,
+                        ));
+                }
+                NoteEvent::PolyPressure {
+                    channel,
+                    note,
+                    pressure,
+                    ..
+                } => {
+                    self.params
+                        .send_interpreter_msg(InterpreterMessage::OnAftertouch(
+                            pressure,
+                            channel,
+                            Some(note),
+                        ));
+                }
+                NoteEvent::MidiProgramChange {
+                    channel, program, ..
+                } => {
+                    self.params
+                        .send_interpreter_msg(InterpreterMessage::OnProgramChange(
+                            program, channel,
+                        ));
+                }
+                NoteEvent::MidiSysEx { message, .. } => {
+                    self.params
+                        .send_interpreter_msg(InterpreterMessage::OnSysEx(message.into()));
+                }
+                _ => (),
+            }
+
+            next_event = context.next_event();
         }
+
+        None
+    }
+}
+
+fn note_off_event(note_off: &NoteOff, timing: u32) -> PluginNoteEvent<Kotoist> {
+    NoteEvent::NoteOff {
+        timing,
+        voice_id: None,
+        channel: note_off.channel,
+        note: note_off.note,
+        velocity: 0.0,
     }
 }
 
+// The transport state of the current block.
+struct BlockTransport {
+    transport: orchestrator::Transport,
+    beats_position: f64,
+    tempo: f64,
+    is_playing: bool,
+    // the transport comes from the internal clock
+    is_internal: bool,
+}
+
 impl From<&Transport> for orchestrator::Transport {
     fn from(value: &Transport) -> Self {
         // beats per second tempo
@@ -228,11 +595,28 @@ impl From<&Transport> for orchestrator::Transport {
         let position = value.pos_samples().unwrap_or_default() as f64;
         let sample_rate = value.sample_rate as f64;
         let beat_length = sample_rate / tempo;
+        let loop_range = value
+            .loop_range_samples()
+            .map(|(start, end)| (start as f64, end as f64))
+            .or_else(|| {
+                value
+                    .loop_range_beats()
+                    .map(|(start, end)| (start * beat_length, end * beat_length))
+            });
+        let time_signature = (
+            value.time_sig_numerator.unwrap_or(4).max(1) as u32,
+            value.time_sig_denominator.unwrap_or(4).max(1) as u32,
+        );
+        let bar = value
+            .bar_start_pos_beats()
+            .zip(value.bar_number())
+            .map(|(start, number)| (start, number as i64));
 
         orchestrator::Transport {
-            position,
-            beat_length,
+            loop_range,
+            ..orchestrator::Transport::new(position, beat_length)
         }
+        .with_bars(time_signature, value.pos_beats().unwrap_or_default(), bar)
     }
 }
 
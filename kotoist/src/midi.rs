@@ -0,0 +1,401 @@
+//! The MIDI state shared between the interpreter and the audio thread.
+use std::collections::VecDeque;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU16, AtomicU64, AtomicU8, Ordering},
+    Arc, Mutex,
+};
+
+use nih_plug::prelude::{AtomicF32, SysExMessage};
+use rtrb::{Consumer, Producer, RingBuffer};
+
+/// The number of messages, which can wait for the audio thread.
+const MIDI_QUEUE_CAPACITY: usize = 1024;
+/// The number of the notes sent by `note`, which can wait for their note-offs.
+const NOTE_OFFS_CAPACITY: usize = 1024;
+
+/// Makes the queue of MIDI messages, which are sent by the interpreter right away, bypassing the
+/// patterns.
+pub(crate) fn new_midi_queue() -> (MidiIn, MidiOut) {
+    let (producer, consumer) = RingBuffer::new(MIDI_QUEUE_CAPACITY);
+    (
+        MidiIn {
+            producer: Arc::new(Mutex::new(producer)),
+        },
+        MidiOut { consumer },
+    )
+}
+
+/// The type for sending the messages from the interpreter.
+#[derive(Clone)]
+pub(crate) struct MidiIn {
+    producer: Arc<Mutex<Producer<MidiMessage>>>,
+}
+
+impl MidiIn {
+    /// Returns `false`, if the queue is full, i.e. the audio thread doesn't keep up.
+    pub(crate) fn send(&self, message: MidiMessage) -> bool {
+        self.producer.lock().unwrap().push(message).is_ok()
+    }
+}
+
+/// The type for reading the messages in the audio thread. It's a lock-free ring buffer, so reading
+/// never blocks or allocates.
+pub(crate) struct MidiOut {
+    consumer: Consumer<MidiMessage>,
+}
+
+impl MidiOut {
+    /// Iterates over the messages sent since the last call.
+    pub(crate) fn pending(&mut self) -> impl Iterator<Item = MidiMessage> + '_ {
+        std::iter::from_fn(|| self.consumer.pop().ok())
+    }
+}
+
+/// The note-off of a note sent by `note`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct NoteOff {
+    /// In samples since the start.
+    pub(crate) position: u64,
+    pub(crate) note: u8,
+    pub(crate) channel: u8,
+}
+
+/// The pending note-offs ordered by position. The storage is allocated once, so it can be used on
+/// the audio thread.
+pub(crate) struct NoteOffs {
+    pending: VecDeque<NoteOff>,
+}
+
+impl Default for NoteOffs {
+    fn default() -> Self {
+        Self {
+            pending: VecDeque::with_capacity(NOTE_OFFS_CAPACITY),
+        }
+    }
+}
+
+impl NoteOffs {
+    /// Adds the note-off. If there's no room, the earliest note-off is returned to be sent right
+    /// away, so the notes never hang.
+    pub(crate) fn insert(&mut self, note_off: NoteOff) -> Option<NoteOff> {
+        let evicted = if self.pending.len() == NOTE_OFFS_CAPACITY {
+            self.pending.pop_front()
+        } else {
+            None
+        };
+        let index = self
+            .pending
+            .partition_point(|pending| pending.position <= note_off.position);
+        self.pending.insert(index, note_off);
+
+        evicted
+    }
+
+    /// Removes and returns the earliest note-off, which is due at the position.
+    pub(crate) fn pop_due(&mut self, position: u64) -> Option<NoteOff> {
+        match self.pending.front() {
+            Some(note_off) if note_off.position <= position => self.pending.pop_front(),
+            _ => None,
+        }
+    }
+}
+
+/// The velocities and the CC values are in the range from 0.0 to 1.0.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum MidiMessage {
+    NoteOn {
+        note: u8,
+        velocity: f32,
+        channel: u8,
+    },
+    NoteOff {
+        note: u8,
+        channel: u8,
+    },
+    /// The note, which is released automatically after `dur` beats.
+    Note {
+        note: u8,
+        velocity: f32,
+        channel: u8,
+        dur: f64,
+    },
+    Cc {
+        cc: u8,
+        value: f32,
+        channel: u8,
+    },
+    SysEx(SysEx),
+}
+
+/// A MIDI system exclusive message, including the leading `0xF0` and the trailing `0xF7` bytes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SysEx(Vec<u8>);
+
+impl SysEx {
+    /// Checks the framing bytes and that the data bytes are 7-bit.
+    pub(crate) fn new(bytes: Vec<u8>) -> Result<Self, String> {
+        match bytes.as_slice() {
+            [0xF0, data @ .., 0xF7] if data.iter().all(|byte| *byte < 0x80) => Ok(Self(bytes)),
+            [0xF0, .., 0xF7] => Err("the data bytes should be less than 0x80".to_string()),
+            _ => Err("the message should start with 0xF0 and end with 0xF7".to_string()),
+        }
+    }
+}
+
+impl From<SysEx> for Vec<u8> {
+    fn from(value: SysEx) -> Self {
+        value.0
+    }
+}
+
+impl SysExMessage for SysEx {
+    type Buffer = Vec<u8>;
+
+    fn from_buffer(buffer: &[u8]) -> Option<Self> {
+        Some(Self(buffer.to_vec()))
+    }
+
+    fn to_buffer(self) -> (Self::Buffer, usize) {
+        let length = self.0.len();
+        (self.0, length)
+    }
+}
+
+/// The state of the incoming MIDI, which is updated by the audio thread and the interpreter.
+#[derive(Default)]
+pub(crate) struct MidiState {
+    pub(crate) thru: MidiThru,
+    pub(crate) held_notes: HeldNotes,
+    pub(crate) cc: CcTable,
+}
+
+/// The MIDI thru state. It's changed by the plugin parameter and the interpreter, whoever changes
+/// it last wins.
+pub(crate) struct MidiThru {
+    enabled: AtomicBool,
+    // a bit per channel
+    channels: AtomicU16,
+}
+
+impl Default for MidiThru {
+    fn default() -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            channels: AtomicU16::new(u16::MAX),
+        }
+    }
+}
+
+impl MidiThru {
+    pub(crate) fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Passes only the events on the channels (from 0 to 15). `None` means all channels.
+    pub(crate) fn set_channels(&self, channels: Option<&[u8]>) {
+        let mask = match channels {
+            Some(channels) => channels
+                .iter()
+                .filter(|ch| **ch < 16)
+                .fold(0, |mask, ch| mask | (1 << ch)),
+            None => u16::MAX,
+        };
+        self.channels.store(mask, Ordering::Relaxed);
+    }
+
+    /// Whether the incoming event on the channel should be passed to the output.
+    pub(crate) fn passes(&self, channel: u8) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+            && channel < 16
+            && self.channels.load(Ordering::Relaxed) & (1 << channel) != 0
+    }
+}
+
+/// The notes currently held on the input (on any channel). A note is held until it's released as
+/// many times as it was pressed, so the same note held on several channels stays held until the
+/// last one is released.
+pub(crate) struct HeldNotes {
+    // a bit per note
+    notes: [AtomicU64; 2],
+    // the number of presses per note, only the audio thread writes them
+    presses: [AtomicU8; 128],
+}
+
+impl Default for HeldNotes {
+    fn default() -> Self {
+        Self {
+            notes: Default::default(),
+            presses: std::array::from_fn(|_| AtomicU8::new(0)),
+        }
+    }
+}
+
+impl HeldNotes {
+    pub(crate) fn press(&self, note: u8) {
+        if let Some((word, bit)) = Self::bit(note) {
+            let presses = &self.presses[note as usize];
+            presses.store(
+                presses.load(Ordering::Relaxed).saturating_add(1),
+                Ordering::Relaxed,
+            );
+            self.notes[word].fetch_or(bit, Ordering::Relaxed);
+        }
+    }
+
+    /// Releases one press of the note, the releases of the notes, which aren't held, are ignored.
+    pub(crate) fn release(&self, note: u8) {
+        if let Some((word, bit)) = Self::bit(note) {
+            let presses = &self.presses[note as usize];
+            let remaining = presses.load(Ordering::Relaxed).saturating_sub(1);
+            presses.store(remaining, Ordering::Relaxed);
+            if remaining == 0 {
+                self.notes[word].fetch_and(!bit, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// The held notes in ascending order, written to the start of the buffer.
+    pub(crate) fn notes<'a>(&self, buffer: &'a mut [u8; 128]) -> &'a [u8] {
+        let mut len = 0;
+        for (word, notes) in self.notes.iter().enumerate() {
+            let mut mask = notes.load(Ordering::Relaxed);
+            while mask != 0 {
+                buffer[len] = (word * 64) as u8 + mask.trailing_zeros() as u8;
+                len += 1;
+                mask &= mask - 1;
+            }
+        }
+        &buffer[..len]
+    }
+
+    /// The lowest held note above the given one, or the lowest held note, if it's `None`.
+    pub(crate) fn next_above(&self, note: Option<u8>) -> Option<u8> {
+        let start = note.map_or(0, |note| note as usize + 1);
+        for word in start / 64..2 {
+            let mut mask = self.notes[word].load(Ordering::Relaxed);
+            if word == start / 64 {
+                mask &= u64::MAX << (start % 64);
+            }
+            if mask != 0 {
+                return Some((word * 64) as u8 + mask.trailing_zeros() as u8);
+            }
+        }
+        None
+    }
+
+    fn bit(note: u8) -> Option<(usize, u64)> {
+        (note < 128).then(|| (note as usize / 64, 1 << (note % 64)))
+    }
+}
+
+/// The latest values of the incoming CCs per channel, from 0.0 to 1.0.
+pub(crate) struct CcTable {
+    values: [[AtomicF32; 128]; 16],
+}
+
+impl Default for CcTable {
+    fn default() -> Self {
+        Self {
+            values: std::array::from_fn(|_| std::array::from_fn(|_| AtomicF32::new(0.0))),
+        }
+    }
+}
+
+impl CcTable {
+    pub(crate) fn set(&self, cc: u8, channel: u8, value: f32) {
+        if let Some(slot) = self.slot(cc, channel) {
+            slot.store(value, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn get(&self, cc: u8, channel: u8) -> f32 {
+        self.slot(cc, channel)
+            .map(|slot| slot.load(Ordering::Relaxed))
+            .unwrap_or_default()
+    }
+
+    fn slot(&self, cc: u8, channel: u8) -> Option<&AtomicF32> {
+        self.values
+            .get(channel as usize)
+            .and_then(|channel| channel.get(cc as usize))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_note_offs() {
+        let note_off = |position, note| NoteOff {
+            position,
+            note,
+            channel: 0,
+        };
+        let mut note_offs = NoteOffs::default();
+        assert_eq!(note_offs.insert(note_off(20, 60)), None);
+        assert_eq!(note_offs.insert(note_off(10, 62)), None);
+        assert_eq!(note_offs.insert(note_off(20, 64)), None);
+
+        // in the order of the positions, the equal ones in the order of insertion
+        assert_eq!(note_offs.pop_due(5), None);
+        assert_eq!(note_offs.pop_due(20), Some(note_off(10, 62)));
+        assert_eq!(note_offs.pop_due(20), Some(note_off(20, 60)));
+        assert_eq!(note_offs.pop_due(20), Some(note_off(20, 64)));
+        assert_eq!(note_offs.pop_due(20), None);
+
+        // the storage doesn't grow, the earliest note-off is evicted instead
+        let capacity = note_offs.pending.capacity();
+        for position in 0..NOTE_OFFS_CAPACITY as u64 {
+            assert_eq!(note_offs.insert(note_off(position + 1, 60)), None);
+        }
+        assert_eq!(note_offs.insert(note_off(0, 62)), Some(note_off(1, 60)));
+        assert_eq!(note_offs.pending.capacity(), capacity);
+        assert_eq!(note_offs.pop_due(0), Some(note_off(0, 62)));
+    }
+
+    #[test]
+    fn test_held_notes() {
+        let held_notes = HeldNotes::default();
+        let mut buffer = [0; 128];
+        assert!(held_notes.notes(&mut buffer).is_empty());
+        assert_eq!(held_notes.next_above(None), None);
+
+        for note in [127, 60, 0, 63, 64, 128] {
+            held_notes.press(note);
+        }
+        assert_eq!(held_notes.notes(&mut buffer), [0, 60, 63, 64, 127]);
+        assert_eq!(held_notes.next_above(None), Some(0));
+        assert_eq!(held_notes.next_above(Some(0)), Some(60));
+        assert_eq!(held_notes.next_above(Some(63)), Some(64));
+        assert_eq!(held_notes.next_above(Some(64)), Some(127));
+        assert_eq!(held_notes.next_above(Some(127)), None);
+
+        held_notes.release(60);
+        held_notes.release(127);
+        assert_eq!(held_notes.notes(&mut buffer), [0, 63, 64]);
+        assert_eq!(held_notes.next_above(Some(0)), Some(63));
+        assert_eq!(held_notes.next_above(Some(64)), None);
+    }
+
+    #[test]
+    fn test_held_notes_presses() {
+        let held_notes = HeldNotes::default();
+        let mut buffer = [0; 128];
+
+        // the note is held on two channels, it's held until both are released
+        held_notes.press(60);
+        held_notes.press(60);
+        held_notes.release(60);
+        assert_eq!(held_notes.notes(&mut buffer), [60]);
+        held_notes.release(60);
+        assert!(held_notes.notes(&mut buffer).is_empty());
+
+        // the release of a note, which isn't held, doesn't count
+        held_notes.release(62);
+        held_notes.press(62);
+        assert_eq!(held_notes.notes(&mut buffer), [62]);
+        held_notes.release(62);
+        assert!(held_notes.notes(&mut buffer).is_empty());
+    }
+}
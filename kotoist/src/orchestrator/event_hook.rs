@@ -0,0 +1,190 @@
+use koto::prelude::*;
+use thiserror::Error;
+
+use super::{Event, EventValue};
+
+/// The Koto function, which observes or alters the events before they're played. The events are
+/// prefetched by the orchestrator and passed through the hook on the interpreter thread, so it
+/// runs with the script, i.e. it sees the top-level values and can call the `kotoist` functions.
+#[derive(Clone)]
+pub(crate) struct EventHook {
+    function: KValue,
+}
+
+impl EventHook {
+    pub(crate) fn new(function: KValue) -> Self {
+        Self { function }
+    }
+
+    /// Passes the event to the function as a map and applies the returned map. `null` turns the
+    /// event into a rest, so the timing of the player isn't changed.
+    pub(crate) fn apply(
+        &self,
+        koto: &mut Koto,
+        event: &mut Event,
+        player: &str,
+    ) -> Result<(), EventHookError> {
+        let map = event_to_koto(event, player);
+
+        match koto.call_function(self.function.clone(), &[map.into()]) {
+            Ok(KValue::Null) => {
+                event.value = vec![EventValue::Rest];
+                Ok(())
+            }
+            Ok(KValue::Map(map)) => apply_koto_map(event, &map),
+            Ok(other) => Err(EventHookError::ReturnType(
+                other.type_as_string().to_string(),
+            )),
+            Err(e) => Err(EventHookError::Koto(e.to_string())),
+        }
+    }
+}
+
+fn event_to_koto(event: &Event, player: &str) -> KMap {
+    let notes: Vec<(u8, u8, u8)> = event
+        .value
+        .iter()
+        .filter_map(|value| match value {
+            EventValue::Note(note, velocity, channel) => Some((*note, *velocity, *channel)),
+            EventValue::Rest => None,
+        })
+        .collect();
+    let pitches: Vec<KValue> = notes.iter().map(|(note, _, _)| (*note).into()).collect();
+    // all the notes of the event share the velocity and the channel
+    let (velocity, channel) = notes
+        .first()
+        .map(|(_, velocity, channel)| (f64::from(*velocity) / 127.0, *channel))
+        .unwrap_or_default();
+
+    let map = KMap::new();
+    map.insert("pitches", KList::from_slice(&pitches));
+    map.insert("velocity", velocity);
+    map.insert("channel", channel);
+    map.insert("dur", event.dur);
+    map.insert("length", event.length);
+    map.insert("player", player);
+
+    map
+}
+
+// The missing keys keep the values of the event.
+fn apply_koto_map(event: &mut Event, map: &KMap) -> Result<(), EventHookError> {
+    let number = |key: &str, default: f64| match map.get(key) {
+        Some(KValue::Number(value)) => Ok(f64::from(value)),
+        Some(KValue::Null) | None => Ok(default),
+        Some(other) => Err(EventHookError::ValueType(
+            key.to_string(),
+            other.type_as_string().to_string(),
+        )),
+    };
+
+    let (velocity, channel) = event
+        .value
+        .iter()
+        .find_map(|value| match value {
+            EventValue::Note(_, velocity, channel) => {
+                Some((f64::from(*velocity) / 127.0, *channel))
+            }
+            EventValue::Rest => None,
+        })
+        .unwrap_or_default();
+    let velocity = (number("velocity", velocity)? * 127.0).clamp(0.0, 127.0) as u8;
+    let channel = number("channel", f64::from(channel))?.clamp(0.0, 15.0) as u8;
+    let dur = number("dur", event.dur)?;
+    if dur < 0.0 {
+        return Err(EventHookError::NegativeDur(dur));
+    }
+    let length = number("length", event.length)?;
+
+    let pitches = match map.get("pitches") {
+        Some(KValue::List(list)) => list
+            .data()
+            .iter()
+            .map(|pitch| match pitch {
+                KValue::Number(pitch) => Ok(f64::from(pitch)),
+                other => Err(EventHookError::ValueType(
+                    "pitches".to_string(),
+                    other.type_as_string().to_string(),
+                )),
+            })
+            .collect::<Result<Vec<f64>, _>>()?,
+        Some(KValue::Number(pitch)) => vec![f64::from(pitch)],
+        Some(KValue::Null) | None => event
+            .value
+            .iter()
+            .filter_map(|value| match value {
+                EventValue::Note(note, _, _) => Some(f64::from(*note)),
+                EventValue::Rest => None,
+            })
+            .collect(),
+        Some(other) => {
+            return Err(EventHookError::ValueType(
+                "pitches".to_string(),
+                other.type_as_string().to_string(),
+            ))
+        }
+    };
+
+    let value: Vec<EventValue> = pitches
+        .into_iter()
+        .filter(|pitch| (0.0..=127.0).contains(pitch))
+        .map(|pitch| EventValue::Note(pitch as u8, velocity, channel))
+        .collect();
+
+    event.value = if value.is_empty() {
+        vec![EventValue::Rest]
+    } else {
+        value
+    };
+    event.dur = dur;
+    event.length = length;
+
+    Ok(())
+}
+
+#[derive(Debug, Error)]
+pub(crate) enum EventHookError {
+    #[error("on_event: {0}")]
+    Koto(String),
+    #[error("on_event: the callback should return a map or null, but it returned '{0}'.")]
+    ReturnType(String),
+    #[error("on_event: expected a number for '{0}', but found '{1}'.")]
+    ValueType(String, String),
+    #[error("on_event: the duration can't be negative, but it's {0}.")]
+    NegativeDur(f64),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_event_map() {
+        let mut event = Event {
+            value: vec![EventValue::Note(60, 127, 1), EventValue::Note(64, 127, 1)],
+            frame_offset: 0,
+            dur: 1.0,
+            length: 0.5,
+        };
+
+        let map = event_to_koto(&event, "lead");
+        assert!(matches!(map.get("velocity"), Some(KValue::Number(n)) if f64::from(n) == 1.0));
+        assert!(matches!(map.get("channel"), Some(KValue::Number(n)) if f64::from(n) == 1.0));
+        assert!(matches!(map.get("player"), Some(KValue::Str(name)) if name.as_str() == "lead"));
+
+        // the missing keys keep the values, the notes out of range are dropped
+        let map = KMap::new();
+        map.insert("pitches", KList::from_slice(&[72.into(), 200.into()]));
+        map.insert("dur", 0.5);
+        apply_koto_map(&mut event, &map).unwrap();
+        assert!(matches!(
+            event.value.as_slice(),
+            [EventValue::Note(72, 127, 1)]
+        ));
+        assert_eq!(event.dur, 0.5);
+        assert_eq!(event.length, 0.5);
+
+        map.insert("dur", -1.0);
+        assert!(apply_koto_map(&mut event, &map).is_err());
+    }
+}
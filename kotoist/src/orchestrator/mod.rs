@@ -1,93 +1,377 @@
+pub(crate) use self::event_hook::EventHook;
+pub(crate) use self::modifiers::Modifiers;
 pub(crate) use self::pattern::{Event, EventValue, Pattern, ScheduledEvent};
+pub(crate) use self::quantization::{Quantization, QuantizationUnit};
 pub(crate) use self::scale::Scale;
+pub(crate) use self::tempo_curve::TempoCurve;
 
+use std::collections::VecDeque;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    Arc,
+};
+
+use fastrand::Rng;
+
+use crate::midi::MidiState;
 use crate::pipe::{Message as PipeMessage, PipeIn};
 
+mod event_hook;
+mod modifiers;
 mod pattern;
+mod quantization;
+pub(crate) mod rng;
 mod scale;
+mod tempo_curve;
+
+/// The number of the events, which are prefetched for the event hook per pattern.
+const PREFETCH_EVENTS: usize = 16;
+/// How far ahead (in the beats of the player) the events are prefetched for the event hook.
+const PREFETCH_BEATS: f64 = 1.0;
+
+// identifies the patterns, to which the prefetched events are returned
+static NEXT_PATTERN_ID: AtomicU64 = AtomicU64::new(0);
 
 pub(crate) struct Orchestrator {
     pipe_in: PipeIn,
+    midi_state: Arc<MidiState>,
     players: Vec<Player>,
+    // whether the events are passed through the event hook, which the interpreter calls
+    is_event_hooked: bool,
     buffer: Vec<Event>,
 }
 
 impl Orchestrator {
-    pub(crate) fn new(pipe_in: PipeIn) -> Self {
+    pub(crate) fn new(pipe_in: PipeIn, midi_state: Arc<MidiState>) -> Self {
         Self {
             pipe_in,
+            midi_state,
             players: Vec::new(),
+            is_event_hooked: false,
             buffer: Vec::with_capacity(512),
         }
     }
 
-    pub(crate) fn set_patterns(&mut self, patterns: Vec<Pattern>, quantization: f64) {
+    /// The players, which don't have the seed set in the pattern, derive it from the `seed`.
+    pub(crate) fn set_patterns(
+        &mut self,
+        patterns: Vec<Pattern>,
+        quantization: Quantization,
+        seed: u64,
+    ) {
         self.players = patterns
             .into_iter()
-            .map(|patt| {
+            .enumerate()
+            .map(|(index, patt)| {
                 let mut player = self
                     .players
                     .pop()
-                    .unwrap_or_else(|| Player::new(self.pipe_in.clone()));
-                player.set_pattern(patt, quantization);
+                    .unwrap_or_else(|| Player::new(self.pipe_in.clone(), self.midi_state.clone()));
+                player.set_pattern(patt, index, quantization, seed);
                 player
             })
             .collect();
     }
 
+    /// The state of the players, which changes only when the patterns are set. Their playback
+    /// state is read as it's updated by `update_players_status`.
+    pub(crate) fn players_status(&self) -> Vec<PlayerStatus> {
+        self.players
+            .iter()
+            .map(|player| PlayerStatus {
+                name: player.name.as_str().into(),
+                quantization: player.quantization,
+                playback: player.playback.clone(),
+            })
+            .collect()
+    }
+
+    /// Updates the playback state of the players. It's called by the audio thread once per block.
+    pub(crate) fn update_players_status(&self) {
+        for player in &self.players {
+            let playback = &player.playback;
+            playback
+                .is_playing
+                .store(player.stream.is_some(), Ordering::Relaxed);
+            playback
+                .pending_notes
+                .store(player.note_offs.len(), Ordering::Relaxed);
+        }
+    }
+
+    /// Copies the patterns, which restart on loop, ahead of the next loop wrap. It's called by the
+    /// interpreter, so the audio thread only swaps the ready-made copy in.
+    pub(crate) fn prepare_restarts(&mut self) {
+        for player in &mut self.players {
+            player.prepare_restart();
+        }
+    }
+
+    /// Sets whether the events should be passed through the event hook. While it's set, the
+    /// players play only the events, which are prefetched and returned by the interpreter.
+    pub(crate) fn set_event_hooked(&mut self, is_hooked: bool) {
+        self.is_event_hooked = is_hooked;
+    }
+
+    /// Pulls the events of the playing and the scheduled patterns ahead, so the interpreter can
+    /// pass them through the event hook. The events are in flight until they're returned by
+    /// `return_prefetched`, the players wait for them meanwhile.
+    pub(crate) fn prefetch(&mut self) -> Vec<PrefetchedEvent> {
+        let mut events = Vec::new();
+        if self.is_event_hooked {
+            for player in &mut self.players {
+                player.prefetch(&mut events);
+            }
+        }
+
+        events
+    }
+
+    /// Queues the prefetched events for playing. The events of the patterns, which were replaced
+    /// in the meantime, are dropped.
+    pub(crate) fn return_prefetched(&mut self, events: Vec<PrefetchedEvent>) {
+        for prefetched in events {
+            let pattern = self.players.iter_mut().find_map(|player| {
+                [player.stream.as_mut(), player.scheduled.as_mut()]
+                    .into_iter()
+                    .flatten()
+                    .find(|pattern| pattern.id == prefetched.pattern_id)
+            });
+            if let Some(pattern) = pattern {
+                pattern.in_flight = pattern.in_flight.saturating_sub(1);
+                pattern.prefetched.push_back(prefetched.event);
+            }
+        }
+    }
+
+    /// Sets the tempo ratio of the player. Returns `false` if there's no such player.
+    pub(crate) fn set_tempo_ratio(&mut self, player: &PlayerId, ratio: f64) -> bool {
+        match self.player_mut(player) {
+            Some(player) => {
+                player.set_tempo_ratio(ratio);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Sets the tempo curve of the player, `None` removes it. Returns `false` if there's no such
+    /// player.
+    pub(crate) fn set_tempo_curve(&mut self, player: &PlayerId, curve: Option<TempoCurve>) -> bool {
+        match self.player_mut(player) {
+            Some(player) => {
+                player.set_tempo_curve(curve);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn player_mut(&mut self, id: &PlayerId) -> Option<&mut Player> {
+        match id {
+            PlayerId::Index(index) => self.players.get_mut(*index),
+            PlayerId::Name(name) => self.players.iter_mut().find(|p| &p.name == name),
+        }
+    }
+
     pub(crate) fn tick(
         &mut self,
         is_playing: bool,
         transport: &Transport,
         frame_offset: usize,
+        modifiers: &Modifiers,
     ) -> &[Event] {
         self.buffer.clear();
 
         self.players
             .iter_mut()
-            .flat_map(|p| p.tick(is_playing, transport, frame_offset))
+            .flat_map(|p| {
+                p.tick(
+                    is_playing,
+                    transport,
+                    frame_offset,
+                    modifiers,
+                    self.is_event_hooked,
+                )
+            })
             .for_each(|e| self.buffer.push(e.clone()));
 
         &self.buffer
     }
 }
 
+/// The event pulled ahead for the event hook.
+pub(crate) struct PrefetchedEvent {
+    pattern_id: u64,
+    pub(crate) player: String,
+    pub(crate) event: Event,
+}
+
+/// The player can be referred either by its index in the list of patterns passed to `midiout` or by
+/// the name set in the pattern.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum PlayerId {
+    Index(usize),
+    Name(String),
+}
+
+/// The state of the player reported to the interpreter.
+#[derive(Debug, Clone)]
+pub(crate) struct PlayerStatus {
+    pub(crate) name: Arc<str>,
+    pub(crate) quantization: Quantization,
+    playback: Arc<PlayerPlayback>,
+}
+
+impl PlayerStatus {
+    pub(crate) fn is_playing(&self) -> bool {
+        self.playback.is_playing.load(Ordering::Relaxed)
+    }
+
+    /// The number of the notes, which are still sounding.
+    pub(crate) fn pending_notes(&self) -> usize {
+        self.playback.pending_notes.load(Ordering::Relaxed)
+    }
+}
+
+// The state of the player, which changes while it plays.
+#[derive(Debug, Default)]
+struct PlayerPlayback {
+    is_playing: AtomicBool,
+    pending_notes: AtomicUsize,
+}
+
 struct Player {
     pipe_in: PipeIn,
-    // 1. user called midiout function and set the pattern with quantization
-    requested: Option<Pattern>,
-    quantization: f64,
+    midi_state: Arc<MidiState>,
+    name: String,
+    // 1. user called midiout function and set the pattern with quantization, along with the copy
+    // used to restart it on loop
+    requested: Option<(Pattern, Option<Pattern>)>,
+    quantization: Quantization,
     // 2. the tick is called and the requested pattern scheduled
     scheduled: Option<ScheduledPattern>,
     // 3. the pattern is what should currently play
     stream: Option<ScheduledPattern>,
     next_note_on_pos: f64,
     last_position: f64,
+    // how fast the player's beats go relative to the host beats
+    tempo_ratio: f64,
+    tempo_curve: Option<TempoCurve>,
+    // the player's position in its own beats since the tempo curve is set
+    beat: f64,
     note_offs: Vec<ScheduledEvent>,
     buffer: Vec<Event>,
+    // the random numbers of the density dropouts, seeded along with the pattern ones
+    density_rng: Rng,
+    // shared with the status of the player
+    playback: Arc<PlayerPlayback>,
 }
 
 impl Player {
-    fn new(pipe_in: PipeIn) -> Self {
+    fn new(pipe_in: PipeIn, midi_state: Arc<MidiState>) -> Self {
         Player {
             pipe_in,
+            midi_state,
+            name: String::new(),
             requested: None,
-            quantization: 0.0,
+            quantization: Quantization::default(),
             scheduled: None,
             stream: None,
             next_note_on_pos: 0.0,
             last_position: 0.0,
+            tempo_ratio: 1.0,
+            tempo_curve: None,
+            beat: 0.0,
             note_offs: Vec::new(),
             buffer: Vec::with_capacity(512),
+            density_rng: Rng::with_seed(0),
+            playback: Default::default(),
         }
     }
 
-    fn set_pattern(&mut self, pattern: Pattern, quantization: f64) {
-        self.requested = Some(pattern);
-        self.quantization = quantization;
+    fn set_pattern(
+        &mut self,
+        mut pattern: Pattern,
+        index: usize,
+        quantization: Quantization,
+        seed: u64,
+    ) {
+        self.name = pattern.name.clone().unwrap_or_else(|| index.to_string());
+        let name = &self.name;
+        pattern
+            .seed
+            .get_or_insert_with(|| rng::player_seed(seed, name));
+        // the quantization set in the pattern takes precedence
+        self.quantization = pattern.quantization.unwrap_or(quantization);
+        let initial = if pattern.restart_on_loop {
+            match pattern.try_copy() {
+                Ok(initial) => Some(initial),
+                Err(e) => {
+                    self.pipe_in.send(PipeMessage::Error(format!("{}\n", e)));
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        self.requested = Some((pattern, initial));
+    }
+
+    // Copies the pattern for the next restart, unless it's copied already.
+    fn prepare_restart(&mut self) {
+        for pattern in [self.stream.as_mut(), self.scheduled.as_mut()]
+            .into_iter()
+            .flatten()
+        {
+            let Some(initial) = &pattern.initial else {
+                continue;
+            };
+            if pattern.restart.is_some() {
+                continue;
+            }
+            match initial.try_copy() {
+                Ok(restart) => pattern.restart = Some(restart),
+                Err(e) => {
+                    pattern.initial = None;
+                    self.pipe_in.send(PipeMessage::Error(format!("{}\n", e)));
+                }
+            }
+        }
+    }
+
+    // The positions of the next note-on and note-offs are rescaled, so the player keeps its phase
+    // (i.e. the part of the current event, which is already played) when the ratio changes.
+    fn set_tempo_ratio(&mut self, ratio: f64) {
+        let position = self.last_position;
+        let scale = self.tempo_ratio / ratio;
+        let rescale = |value: &mut f64| {
+            if *value > position {
+                *value = position + (*value - position) * scale;
+            }
+        };
+
+        rescale(&mut self.next_note_on_pos);
+        self.note_offs
+            .iter_mut()
+            .for_each(|note_off| rescale(&mut note_off.position));
+        self.tempo_ratio = ratio;
     }
 
-    fn tick(&mut self, is_playing: bool, transport: &Transport, frame_offset: usize) -> &[Event] {
+    // The curve starts at the next event, the events already scheduled aren't affected.
+    fn set_tempo_curve(&mut self, curve: Option<TempoCurve>) {
+        self.tempo_curve = curve;
+        self.beat = 0.0;
+    }
+
+    fn tick(
+        &mut self,
+        is_playing: bool,
+        transport: &Transport,
+        frame_offset: usize,
+        modifiers: &Modifiers,
+        is_event_hooked: bool,
+    ) -> &[Event] {
         if !is_playing {
             self.next_note_on_pos -= self.last_position;
             self.buffer.clear();
@@ -107,9 +391,12 @@ impl Player {
             return &self.buffer;
         }
 
-        if let Some(pattern) = self.requested.take() {
-            let position = quantized_position(self.quantization, transport, frame_offset);
-            self.scheduled = Some(ScheduledPattern { position, pattern });
+        if let Some((pattern, initial)) = self.requested.take() {
+            let position = quantized_position(&self.quantization, transport, frame_offset);
+            self.scheduled = Some(ScheduledPattern {
+                initial,
+                ..ScheduledPattern::new(position, pattern)
+            });
         }
 
         self.adjust_position(transport, frame_offset);
@@ -118,13 +405,11 @@ impl Player {
 
         self.buffer.clear();
 
-        let mut note_offs = self.note_offs_at(self.last_position);
+        let mut note_offs = self.note_offs_at(self.last_position, frame_offset);
 
         self.buffer.append(&mut note_offs);
 
-        if let Some(event) =
-            self.next_event(frame_offset, transport.position, transport.beat_length)
-        {
+        if let Some(event) = self.next_event(frame_offset, transport, modifiers, is_event_hooked) {
             self.buffer.push(event.event);
         }
 
@@ -133,10 +418,15 @@ impl Player {
 
     // adjust next note-on position on cursor jump
     fn adjust_position(&mut self, transport: &Transport, frame_offset: usize) {
-        // if the difference is more than two samples, we consider it a jump
         let position = transport.position + frame_offset as f64;
-        if (position - self.last_position).abs() > 2.0 {
-            self.next_note_on_pos = quantized_position(self.quantization, transport, frame_offset);
+        if is_jump(self.last_position, position) {
+            if self.is_loop_wrap(transport, position) {
+                self.on_loop_wrap(position);
+                self.last_position = position;
+                return;
+            }
+
+            self.next_note_on_pos = quantized_position(&self.quantization, transport, frame_offset);
             // call note off for all notes
             self.note_offs
                 .iter_mut()
@@ -146,7 +436,44 @@ impl Player {
         self.last_position = position;
     }
 
-    fn note_offs_at(&mut self, position: f64) -> Vec<Event> {
+    fn is_loop_wrap(&self, transport: &Transport, position: f64) -> bool {
+        match transport.loop_range {
+            Some((start, end)) => {
+                position < self.last_position
+                    && self.last_position + 2.0 >= end
+                    && position >= start - 2.0
+            }
+            None => false,
+        }
+    }
+
+    // the host jumped from the loop end to the loop start
+    fn on_loop_wrap(&mut self, position: f64) {
+        self.next_note_on_pos = position;
+        // the note offs are clamped to the loop end, so anything left should be sent right away
+        self.note_offs
+            .iter_mut()
+            .for_each(|v| v.position = position);
+
+        // restart the pattern, if it's requested and the copy is prepared by the interpreter
+        let Some(stream) = self.stream.as_mut() else {
+            return;
+        };
+        let Some(mut pattern) = stream.restart.take() else {
+            return;
+        };
+
+        self.density_rng = Rng::with_seed(rng::density_seed(pattern.seed.unwrap_or_default()));
+        let tempo_curve = pattern.tempo_curve.take();
+        // the events prefetched from the previous pass are dropped
+        *stream = ScheduledPattern {
+            initial: stream.initial.take(),
+            ..ScheduledPattern::new(stream.position, pattern)
+        };
+        self.set_tempo_curve(tempo_curve);
+    }
+
+    fn note_offs_at(&mut self, position: f64, frame_offset: usize) -> Vec<Event> {
         let (current_offs, scheduled_offs) = self
             .note_offs
             .iter()
@@ -155,17 +482,27 @@ impl Player {
 
         self.note_offs = scheduled_offs;
 
-        current_offs.into_iter().map(|e| e.event).collect()
+        current_offs
+            .into_iter()
+            .map(|mut e| {
+                e.event.frame_offset = frame_offset;
+                e.event
+            })
+            .collect()
     }
 
     /// try to queue pattern
     fn try_queue(&mut self, position: f64) {
-        if let Some(stream) = self.scheduled.take() {
+        if let Some(mut stream) = self.scheduled.take() {
             if position < stream.position {
                 self.scheduled = Some(stream);
                 return;
             }
 
+            let tempo_ratio = stream.pattern.tempo_ratio;
+            let tempo_curve = stream.pattern.tempo_curve.take();
+            self.density_rng =
+                Rng::with_seed(rng::density_seed(stream.pattern.seed.unwrap_or_default()));
             self.stream = Some(stream);
 
             // the pattern should start playing immediately at the scheduled position. so we need to
@@ -184,22 +521,50 @@ impl Player {
                     note_off.position
                 }
             }
+
+            // the ratio set at runtime is kept, unless the pattern sets its own
+            if let Some(tempo_ratio) = tempo_ratio {
+                self.set_tempo_ratio(tempo_ratio);
+            }
+            self.set_tempo_curve(tempo_curve);
         }
     }
 
     fn next_event(
         &mut self,
         frame_offset: usize,
-        position: f64,
-        beat_length: f64,
+        transport: &Transport,
+        modifiers: &Modifiers,
+        is_event_hooked: bool,
     ) -> Option<ScheduledEvent> {
         if let Some(stream) = &mut self.stream {
-            if position < self.next_note_on_pos {
+            if transport.position < self.next_note_on_pos {
                 return None;
             }
 
-            match stream.pattern.try_next(frame_offset) {
-                Ok(event) => return event.map(|e| self.schedule_events(position, beat_length, e)),
+            let held_notes = &self.midi_state.held_notes;
+            let next = match stream.prefetched.pop_front() {
+                Some(mut event) => {
+                    event.frame_offset = frame_offset;
+                    Ok(Some(event))
+                }
+                // the event isn't returned by the interpreter yet, so it's played late
+                None if stream.in_flight > 0 || (is_event_hooked && !stream.is_ended) => {
+                    return None
+                }
+                None if stream.is_ended => Ok(None),
+                None => rng::with_rng(&mut stream.rng, || {
+                    stream.pattern.try_next(frame_offset, held_notes)
+                }),
+            };
+
+            match next {
+                Ok(event) => {
+                    return event.map(|mut e| {
+                        modifiers.apply(&mut e, &mut self.density_rng);
+                        self.schedule_events(transport, e)
+                    })
+                }
                 Err(e) => {
                     // we need to remove stream here, as subsequent calls of next will crash Koto
                     self.stream = None;
@@ -212,18 +577,61 @@ impl Player {
         None
     }
 
-    fn schedule_events(&mut self, position: f64, beat_length: f64, event: Event) -> ScheduledEvent {
-        let end = event.dur * beat_length;
+    // The events are pulled up to `PREFETCH_BEATS` ahead of the ones waiting to be played.
+    fn prefetch(&mut self, events: &mut Vec<PrefetchedEvent>) {
+        let held_notes = &self.midi_state.held_notes;
+        let patterns = [self.stream.as_mut(), self.scheduled.as_mut()];
+        for pattern in patterns.into_iter().flatten() {
+            let mut count = pattern.prefetched.len() + pattern.in_flight;
+            let mut beats: f64 = pattern.prefetched.iter().map(|event| event.dur).sum();
+            while !pattern.is_ended && count < PREFETCH_EVENTS && beats < PREFETCH_BEATS {
+                match rng::with_rng(&mut pattern.rng, || pattern.pattern.try_next(0, held_notes)) {
+                    Ok(Some(event)) => {
+                        count += 1;
+                        beats += event.dur;
+                        pattern.in_flight += 1;
+                        events.push(PrefetchedEvent {
+                            pattern_id: pattern.id,
+                            player: self.name.clone(),
+                            event,
+                        });
+                    }
+                    Ok(None) => pattern.is_ended = true,
+                    Err(e) => {
+                        // the pattern can't be pulled anymore, so it ends
+                        pattern.is_ended = true;
+                        self.pipe_in.send(PipeMessage::Error(format!("{}\n", e)));
+                    }
+                }
+            }
+        }
+    }
+
+    fn schedule_events(&mut self, transport: &Transport, event: Event) -> ScheduledEvent {
+        let position = transport.position;
+        self.schedule_note_offs(position, transport, event.clone());
+        let end = self.host_beats(event.dur) * self.beat_length(transport);
         self.next_note_on_pos = position + end;
-        self.schedule_note_offs(position, beat_length, event.clone());
+        self.beat += event.dur;
 
         ScheduledEvent { position, event }
     }
 
-    fn schedule_note_offs(&mut self, note_on_position: f64, beat_length: f64, mut event: Event) {
-        let end = event.length * event.dur * beat_length;
-        let position = note_on_position + end;
-        event.frame_offset += end as usize;
+    fn schedule_note_offs(
+        &mut self,
+        note_on_position: f64,
+        transport: &Transport,
+        mut event: Event,
+    ) {
+        let end = self.host_beats(event.length * event.dur) * self.beat_length(transport);
+        let mut position = note_on_position + end;
+        // the note should be released before the host jumps back to the loop start, otherwise it
+        // will hang or overlap with the next iteration
+        if let Some((_, loop_end)) = transport.loop_range {
+            if note_on_position < loop_end && position >= loop_end {
+                position = loop_end - 1.0;
+            }
+        }
         event.value.iter_mut().for_each(|e| {
             if let EventValue::Note(_, v, _) = e {
                 *v = 0;
@@ -231,24 +639,85 @@ impl Player {
         });
         self.note_offs.push(ScheduledEvent { position, event });
     }
+
+    // the duration in host beats of the player's beats starting from the current beat
+    fn host_beats(&mut self, beats: f64) -> f64 {
+        let curve = match self.tempo_curve.as_mut() {
+            Some(curve) => curve,
+            None => return beats,
+        };
+
+        match curve.host_beats(self.beat, self.beat + beats) {
+            Ok(host_beats) => host_beats,
+            Err(e) => {
+                // the curve can't be evaluated anymore, so the player goes on without it
+                self.tempo_curve = None;
+                self.pipe_in.send(PipeMessage::Error(format!("{}\n", e)));
+                beats
+            }
+        }
+    }
+
+    // the length of the player's beat in samples
+    fn beat_length(&self, transport: &Transport) -> f64 {
+        transport.beat_length / self.tempo_ratio
+    }
+}
+
+/// Whether the position (in samples) doesn't follow the last one, i.e. the host cursor jumped.
+pub(crate) fn is_jump(last_position: f64, position: f64) -> bool {
+    // if the difference is more than two samples, we consider it a jump
+    (position - last_position).abs() > 2.0
 }
 
 // get next quantazied position - i.e. the position at which the pattern should play taking the
 // quantization into account
-fn quantized_position(quantization: f64, transport: &Transport, frame_offset: usize) -> f64 {
-    let position = transport.position + frame_offset as f64;
-    if quantization == 0.0 {
-        return position;
-    }
-    let quant_samples = quantization * transport.beat_length;
-    let offset = quant_samples - (position % quant_samples);
-    position + offset
+fn quantized_position(
+    quantization: &Quantization,
+    transport: &Transport,
+    frame_offset: usize,
+) -> f64 {
+    quantization.next_position(
+        transport.position + frame_offset as f64,
+        transport.beat_length,
+        transport.bar_origin(),
+        transport.bar_length(),
+    )
 }
 
 #[derive(Debug)]
 struct ScheduledPattern {
+    id: u64,
     position: f64,
     pattern: Pattern,
+    // the random numbers of the pattern, seeded when it's scheduled
+    rng: Rng,
+    // the events passed through the event hook, which wait to be played
+    prefetched: VecDeque<Event>,
+    // the number of the prefetched events, which aren't returned by the interpreter yet
+    in_flight: usize,
+    // whether the pattern ended, while its events were prefetched
+    is_ended: bool,
+    // the copy of the pattern, taken before it started, and the copy of it, which is swapped in
+    // on the next loop wrap
+    initial: Option<Pattern>,
+    restart: Option<Pattern>,
+}
+
+impl ScheduledPattern {
+    fn new(position: f64, pattern: Pattern) -> Self {
+        Self {
+            id: NEXT_PATTERN_ID.fetch_add(1, Ordering::Relaxed),
+            position,
+            rng: Rng::with_seed(pattern.seed.unwrap_or_default()),
+            pattern,
+            prefetched: VecDeque::new(),
+            in_flight: 0,
+            is_ended: false,
+            initial: None,
+            restart: None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -257,10 +726,77 @@ pub(crate) struct Transport {
     pub(crate) beat_length: f64,
     // in samples
     pub(crate) position: f64,
+    // loop start and end in samples, if the host loop is active
+    pub(crate) loop_range: Option<(f64, f64)>,
+    // numerator and denominator
+    pub(crate) time_signature: (u32, u32),
+    // the start of the current bar in samples
+    pub(crate) bar_start: f64,
+    pub(crate) bar_number: i64,
+}
+
+impl Transport {
+    /// Makes transport in 4/4 with the bars counted from the beginning.
+    pub(crate) fn new(position: f64, beat_length: f64) -> Self {
+        let transport = Self {
+            beat_length,
+            position,
+            loop_range: None,
+            time_signature: (4, 4),
+            bar_start: 0.0,
+            bar_number: 0,
+        };
+        transport.with_bars((4, 4), position / beat_length, None)
+    }
+
+    /// Sets the time signature and the current bar. If the bar (its start in beats and number)
+    /// is unknown, the bars are counted from the beginning.
+    pub(crate) fn with_bars(
+        mut self,
+        time_signature: (u32, u32),
+        beats_position: f64,
+        bar: Option<(f64, i64)>,
+    ) -> Self {
+        self.time_signature = (time_signature.0.max(1), time_signature.1.max(1));
+        let bar_length_in_beats = self.bar_length() / self.beat_length;
+        let (bar_start_in_beats, bar_number) = bar.unwrap_or_else(|| {
+            let bar_number = (beats_position / bar_length_in_beats).floor();
+            (bar_number * bar_length_in_beats, bar_number as i64)
+        });
+        self.bar_start = self.position - (beats_position - bar_start_in_beats) * self.beat_length;
+        self.bar_number = bar_number;
+        self
+    }
+
+    /// The start of the bar 0 in samples, from which the bars are counted.
+    pub(crate) fn bar_origin(&self) -> f64 {
+        self.bar_start - self.bar_number as f64 * self.bar_length()
+    }
+
+    /// Bar length in samples.
+    pub(crate) fn bar_length(&self) -> f64 {
+        let (numerator, denominator) = self.time_signature;
+        self.beat_length * numerator as f64 * 4.0 / denominator as f64
+    }
+
+    /// Returns the bar number and the beat within the bar (counted in the time signature
+    /// denominator) at the given position in samples.
+    pub(crate) fn bar_and_beat(&self, position: f64) -> (i64, u32) {
+        let bar_length = self.bar_length();
+        let since_bar_start = position - self.bar_start;
+        let bars = (since_bar_start / bar_length).floor();
+        let beat_length = bar_length / self.time_signature.0 as f64;
+        let beat = ((since_bar_start - bars * bar_length) / beat_length).floor();
+        (self.bar_number + bars as i64, beat as u32)
+    }
 }
 
 #[cfg(test)]
 mod test {
+    use std::sync::Mutex;
+
+    use koto::runtime::{KIterator, KIteratorOutput, KMap, KotoIterator};
+
     use super::*;
 
     #[test]
@@ -268,19 +804,19 @@ mod test {
         let sample_rate = 44100.0;
         let bps = 60.0 / 120.0; // beats per second
         let beat_length = bps * sample_rate;
-        let mut transport = Transport {
-            beat_length,
-            position: 1.0,
-        };
+        let mut transport = Transport::new(1.0, beat_length);
 
-        assert_eq!(quantized_position(1.0, &transport, 0), beat_length);
+        assert_eq!(
+            quantized_position(&Quantization::beats(1.0), &transport, 0),
+            beat_length
+        );
 
         for n in 0..100 {
             transport.position = 42.0 * (n as f64 / 100.0) * sample_rate;
             let quant = 1.5;
             let quant_samples = quant * beat_length;
             assert_eq!(
-                quantized_position(quant, &transport, 0) % quant_samples,
+                quantized_position(&Quantization::beats(quant), &transport, 0) % quant_samples,
                 0.0
             );
         }
@@ -288,8 +824,260 @@ mod test {
         transport.position = sample_rate;
         let quant_samples = beat_length; // 1.0 s * beat_length
         assert_eq!(
-            quantized_position(1.0, &transport, 0),
+            quantized_position(&Quantization::beats(1.0), &transport, 0),
             sample_rate + quant_samples
         );
+
+        // 7/8 - the bar is 3.5 beats long
+        let transport = Transport::new(5.0 * beat_length, beat_length).with_bars((7, 8), 5.0, None);
+        assert_eq!(transport.bar_number, 1);
+        assert_eq!(transport.bar_start, 3.5 * beat_length);
+        assert_eq!(transport.bar_and_beat(transport.position), (1, 3));
+        assert_eq!(
+            quantized_position(&Quantization::bars(1.0), &transport, 0),
+            7.0 * beat_length
+        );
+        assert_eq!(
+            quantized_position(&Quantization::bars(2.0), &transport, 0),
+            7.0 * beat_length
+        );
+        assert_eq!(
+            quantized_position(&Quantization::bars(3.0), &transport, 0),
+            10.5 * beat_length
+        );
+
+        // the next multiple of 4 beats plus 1.5
+        let transport = Transport::new(beat_length, beat_length);
+        let quant = Quantization::beats(4.0).with_phase(1.5);
+        assert_eq!(quantized_position(&quant, &transport, 0), 1.5 * beat_length);
+        let transport = Transport::new(2.0 * beat_length, beat_length);
+        assert_eq!(quantized_position(&quant, &transport, 0), 5.5 * beat_length);
+
+        // anticipate the downbeat
+        let quant = Quantization::beats(4.0).with_offset(-0.25);
+        assert_eq!(
+            quantized_position(&quant, &transport, 0),
+            3.75 * beat_length
+        );
+        let transport = Transport::new(3.9 * beat_length, beat_length);
+        assert_eq!(
+            quantized_position(&quant, &transport, 0),
+            7.75 * beat_length
+        );
+
+        // the "and" of the bar in 7/8
+        let transport = Transport::new(5.0 * beat_length, beat_length).with_bars((7, 8), 5.0, None);
+        let quant = Quantization::bars(1.0).with_phase(0.5);
+        assert_eq!(quantized_position(&quant, &transport, 0), 7.5 * beat_length);
+
+        // no grid, only the delay applies
+        let transport = Transport::new(2.0 * beat_length, beat_length);
+        let quant = Quantization::beats(0.0).with_offset(0.5);
+        assert_eq!(quantized_position(&quant, &transport, 0), 2.5 * beat_length);
+        let quant = Quantization::beats(0.0).with_offset(-0.5);
+        assert_eq!(quantized_position(&quant, &transport, 0), 2.0 * beat_length);
+    }
+
+    #[test]
+    fn test_loop_wrap() {
+        let (pipe_in, _pipe_out) = crate::pipe::new_pipe();
+        let mut player = Player::new(pipe_in, Default::default());
+        let beat_length = 22050.0;
+        let transport = Transport {
+            loop_range: Some((4.0 * beat_length, 8.0 * beat_length)),
+            ..Transport::new(4.0 * beat_length, beat_length)
+        };
+
+        player.last_position = 8.0 * beat_length - 1.0;
+        assert!(player.is_loop_wrap(&transport, transport.position));
+
+        // jumping backwards outside of the loop isn't a wrap
+        player.last_position = 6.0 * beat_length;
+        assert!(!player.is_loop_wrap(&transport, transport.position));
+
+        let no_loop = Transport {
+            loop_range: None,
+            ..transport
+        };
+        player.last_position = 8.0 * beat_length - 1.0;
+        assert!(!player.is_loop_wrap(&no_loop, no_loop.position));
+    }
+
+    #[test]
+    fn test_players_status() {
+        let (pipe_in, _pipe_out) = crate::pipe::new_pipe();
+        let mut orchestrator = Orchestrator::new(pipe_in, Default::default());
+        let map = KMap::new();
+        map.insert("name", "lead");
+        let patterns = vec![Pattern::try_from(&map).unwrap()];
+        orchestrator.set_patterns(patterns, Quantization::default(), 0);
+
+        let status = orchestrator.players_status();
+        assert_eq!(status.len(), 1);
+        assert_eq!(&*status[0].name, "lead");
+        assert_eq!(status[0].quantization, Quantization::default());
+        assert!(!status[0].is_playing());
+
+        // the playback state is read through the published status
+        let transport = Transport::new(0.0, 22050.0);
+        orchestrator.tick(true, &transport, 0, &Modifiers::default());
+        orchestrator.update_players_status();
+        assert!(status[0].is_playing());
+        assert_eq!(status[0].pending_notes(), 1);
+    }
+
+    #[test]
+    fn test_density_rng() {
+        // records the random numbers it yields
+        #[derive(Clone)]
+        struct Numbers(Arc<Mutex<Vec<f64>>>);
+
+        impl KotoIterator for Numbers {
+            fn make_copy(&self) -> koto::runtime::Result<KIterator> {
+                Ok(KIterator::new(self.clone()))
+            }
+        }
+
+        impl Iterator for Numbers {
+            type Item = KIteratorOutput;
+
+            fn next(&mut self) -> Option<Self::Item> {
+                let number = rng::number().unwrap_or(-1.0);
+                self.0.lock().unwrap().push(number);
+                Some(KIteratorOutput::Value(number.into()))
+            }
+        }
+
+        let pulled = |density: f32| {
+            let numbers = Arc::new(Mutex::new(Vec::new()));
+            let map = KMap::new();
+            map.insert("degree", KIterator::new(Numbers(numbers.clone())));
+            let (pipe_in, _pipe_out) = crate::pipe::new_pipe();
+            let mut orchestrator = Orchestrator::new(pipe_in, Default::default());
+            let patterns = vec![Pattern::try_from(&map).unwrap()];
+            orchestrator.set_patterns(patterns, Quantization::default(), 1);
+
+            let modifiers = Modifiers {
+                density,
+                ..Default::default()
+            };
+            for position in 0..1000 {
+                let transport = Transport::new(position as f64, 100.0);
+                orchestrator.tick(true, &transport, 0, &modifiers);
+            }
+
+            let numbers = numbers.lock().unwrap().clone();
+            numbers
+        };
+
+        // the dropouts don't draw from the generator of the pattern
+        let numbers = pulled(1.0);
+        assert_eq!(numbers.len(), 10);
+        assert!(numbers.iter().all(|number| (0.0..1.0).contains(number)));
+        assert_eq!(pulled(0.5), numbers);
+    }
+
+    #[test]
+    fn test_tempo_ratio() {
+        let (pipe_in, _pipe_out) = crate::pipe::new_pipe();
+        let mut player = Player::new(pipe_in, Default::default());
+        let beat_length = 22050.0;
+
+        player.last_position = 2.0 * beat_length;
+        player.next_note_on_pos = 3.0 * beat_length;
+        player.set_tempo_ratio(2.0);
+        // the remaining beat is played twice faster
+        assert_eq!(player.next_note_on_pos, 2.5 * beat_length);
+        assert_eq!(
+            player.beat_length(&Transport::new(0.0, beat_length)),
+            beat_length / 2.0
+        );
+
+        player.set_tempo_ratio(0.5);
+        assert_eq!(player.next_note_on_pos, 4.0 * beat_length);
+
+        // queueing a pattern without the ratio keeps the one set at runtime
+        let queue = |player: &mut Player, map: &KMap| {
+            player.scheduled = Some(ScheduledPattern::new(0.0, Pattern::try_from(map).unwrap()));
+            player.try_queue(player.last_position);
+        };
+        queue(&mut player, &KMap::new());
+        assert_eq!(player.tempo_ratio, 0.5);
+
+        let map = KMap::new();
+        map.insert("tempo_ratio", 1.5);
+        queue(&mut player, &map);
+        assert_eq!(player.tempo_ratio, 1.5);
+    }
+
+    #[test]
+    fn test_restart_on_loop() {
+        // counts the degrees up and the copies made of it
+        #[derive(Clone)]
+        struct Count {
+            degree: i64,
+            copies: Arc<AtomicUsize>,
+        }
+
+        impl KotoIterator for Count {
+            fn make_copy(&self) -> koto::runtime::Result<KIterator> {
+                self.copies.fetch_add(1, Ordering::Relaxed);
+                Ok(KIterator::new(self.clone()))
+            }
+        }
+
+        impl Iterator for Count {
+            type Item = KIteratorOutput;
+
+            fn next(&mut self) -> Option<Self::Item> {
+                self.degree += 1;
+                Some(KIteratorOutput::Value((self.degree - 1).into()))
+            }
+        }
+
+        let copies = Arc::new(AtomicUsize::new(0));
+        let map = KMap::new();
+        let count = Count {
+            degree: 0,
+            copies: copies.clone(),
+        };
+        map.insert("degree", KIterator::new(count));
+        map.insert("restart_on_loop", true);
+        let (pipe_in, _pipe_out) = crate::pipe::new_pipe();
+        let mut orchestrator = Orchestrator::new(pipe_in, Default::default());
+        let patterns = vec![Pattern::try_from(&map).unwrap()];
+        orchestrator.set_patterns(patterns, Quantization::default(), 0);
+
+        // plays the 4 beats loop, a beat is 100 samples
+        let play_loop = |orchestrator: &mut Orchestrator| {
+            (0..400)
+                .flat_map(|position| {
+                    let transport = Transport {
+                        loop_range: Some((0.0, 400.0)),
+                        ..Transport::new(position as f64, 100.0)
+                    };
+                    orchestrator
+                        .tick(true, &transport, 0, &Modifiers::default())
+                        .to_vec()
+                })
+                .flat_map(|event| event.value)
+                .filter_map(|value| match value {
+                    EventValue::Note(pitch, velocity, _) if velocity > 0 => Some(pitch),
+                    _ => None,
+                })
+                .collect::<Vec<u8>>()
+        };
+
+        let first = play_loop(&mut orchestrator);
+        assert_eq!(first.len(), 4);
+
+        // the copy is prepared ahead, the audio thread doesn't copy the pattern on the wrap
+        orchestrator.prepare_restarts();
+        let prepared = copies.load(Ordering::Relaxed);
+        assert_eq!(play_loop(&mut orchestrator), first);
+        assert_eq!(copies.load(Ordering::Relaxed), prepared);
+
+        // without the prepared copy, the pattern goes on
+        assert_ne!(play_loop(&mut orchestrator), first);
     }
 }
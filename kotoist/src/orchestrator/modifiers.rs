@@ -0,0 +1,112 @@
+use fastrand::Rng;
+
+use super::{Event, EventValue};
+
+/// The global modifications applied to every event produced by the patterns.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Modifiers {
+    /// In semitones.
+    pub(crate) transpose: i32,
+    pub(crate) velocity_scale: f32,
+    pub(crate) mute: bool,
+    /// The probability of an event to be played, the dropped events become rests.
+    pub(crate) density: f32,
+}
+
+impl Default for Modifiers {
+    fn default() -> Self {
+        Self {
+            transpose: 0,
+            velocity_scale: 1.0,
+            mute: false,
+            density: 1.0,
+        }
+    }
+}
+
+impl Modifiers {
+    pub(crate) fn apply(&self, event: &mut Event, rng: &mut Rng) {
+        // the whole event (e.g. a chord) is dropped at once
+        let is_dropped = self.mute || (self.density < 1.0 && rng.f32() >= self.density);
+
+        for value in event.value.iter_mut() {
+            if let EventValue::Note(note, velocity, channel) = *value {
+                let note = i32::from(note) + self.transpose;
+                let velocity = (f32::from(velocity) * self.velocity_scale)
+                    .round()
+                    .min(127.0) as u8;
+
+                *value = if is_dropped || !(0..=127).contains(&note) || velocity == 0 {
+                    EventValue::Rest
+                } else {
+                    EventValue::Note(note as u8, velocity, channel)
+                };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn event() -> Event {
+        Event {
+            value: vec![
+                EventValue::Note(60, 100, 0),
+                EventValue::Rest,
+                EventValue::Note(120, 10, 1),
+            ],
+            frame_offset: 0,
+            dur: 1.0,
+            length: 1.0,
+        }
+    }
+
+    fn notes(event: &Event) -> Vec<Option<(u8, u8, u8)>> {
+        event
+            .value
+            .iter()
+            .map(|value| match value {
+                EventValue::Note(note, velocity, channel) => Some((*note, *velocity, *channel)),
+                EventValue::Rest => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_modifiers() {
+        let mut e = event();
+        Modifiers::default().apply(&mut e, &mut Rng::with_seed(0));
+        assert_eq!(
+            notes(&e),
+            vec![Some((60, 100, 0)), None, Some((120, 10, 1))]
+        );
+
+        let mut e = event();
+        Modifiers {
+            transpose: 12,
+            velocity_scale: 2.0,
+            ..Default::default()
+        }
+        .apply(&mut e, &mut Rng::with_seed(0));
+        // the notes out of the range are dropped
+        assert_eq!(notes(&e), vec![Some((72, 127, 0)), None, None]);
+
+        let mut e = event();
+        Modifiers {
+            mute: true,
+            ..Default::default()
+        }
+        .apply(&mut e, &mut Rng::with_seed(0));
+        assert_eq!(notes(&e), vec![None, None, None]);
+
+        let mut e = event();
+        Modifiers {
+            density: 0.0,
+            ..Default::default()
+        }
+        .apply(&mut e, &mut Rng::with_seed(0));
+        assert_eq!(notes(&e), vec![None, None, None]);
+    }
+}
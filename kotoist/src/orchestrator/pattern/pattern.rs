@@ -1,9 +1,10 @@
 use std::convert::TryFrom;
 
-use koto::runtime::KMap;
+use koto::runtime::{KMap, KValue};
 
 use super::stream::*;
-use crate::orchestrator::Scale;
+use crate::midi::HeldNotes;
+use crate::orchestrator::{Quantization, Scale, TempoCurve};
 
 #[derive(Debug)]
 pub(crate) struct Pattern {
@@ -17,10 +18,28 @@ pub(crate) struct Pattern {
     octave: StreamF64,
     channel: StreamF64,
     amp: StreamF64,
+    /// Whether the pattern should start over when the host loop wraps around.
+    pub(crate) restart_on_loop: bool,
+    /// The quantization of this pattern, if it differs from the one passed to `midiout`.
+    pub(crate) quantization: Option<Quantization>,
+    /// The name of the player, which plays this pattern.
+    pub(crate) name: Option<String>,
+    /// How fast the pattern goes relative to the host tempo, `None` keeps the ratio of the player.
+    pub(crate) tempo_ratio: Option<f64>,
+    /// The tempo envelope, which is applied on top of the tempo ratio.
+    pub(crate) tempo_curve: Option<TempoCurve>,
+    /// The seed of the random numbers of the player, `None` derives it from the global seed.
+    pub(crate) seed: Option<u64>,
+    /// Whether the degrees index the notes held on the input instead of the scale.
+    held: bool,
 }
 
 impl Pattern {
-    pub(crate) fn try_next(&mut self, frame_offset: usize) -> Result<Option<Event>, Error> {
+    pub(crate) fn try_next(
+        &mut self,
+        frame_offset: usize,
+        held_notes: &HeldNotes,
+    ) -> Result<Option<Event>, Error> {
         macro_rules! extract_value {
             ($name:ident) => {
                 match self.$name.try_next()? {
@@ -43,7 +62,13 @@ impl Pattern {
 
         let velocity = (127.0 * amp).clamp(0.0, 127.0) as u8;
 
-        let pitches = self.make_pitches(degree, root, octave, scale, transpose, mtranspose);
+        let pitches = if self.held {
+            let mut buffer = [0; 128];
+            let held = held_notes.notes(&mut buffer);
+            Self::make_held_pitches(degree, held, transpose, mtranspose)
+        } else {
+            self.make_pitches(degree, root, octave, scale, transpose, mtranspose)
+        };
 
         let value: Vec<EventValue> = pitches
             .iter()
@@ -61,6 +86,56 @@ impl Pattern {
         }))
     }
 
+    /// Makes a copy of the pattern with all the streams copied at their current state.
+    pub(crate) fn try_copy(&self) -> Result<Self, Error> {
+        Ok(Self {
+            dur: self.dur.try_copy()?,
+            length: self.length.try_copy()?,
+            degree: self.degree.try_copy()?,
+            scale: self.scale.try_copy()?,
+            root: self.root.try_copy()?,
+            transpose: self.transpose.try_copy()?,
+            mtranspose: self.mtranspose.try_copy()?,
+            octave: self.octave.try_copy()?,
+            channel: self.channel.try_copy()?,
+            amp: self.amp.try_copy()?,
+            restart_on_loop: self.restart_on_loop,
+            quantization: self.quantization,
+            name: self.name.clone(),
+            tempo_ratio: self.tempo_ratio,
+            tempo_curve: self
+                .tempo_curve
+                .as_ref()
+                .map(|curve| curve.try_copy())
+                .transpose()
+                .map_err(|e| Error::Other(format!("{}", e)))?,
+            seed: self.seed,
+            held: self.held,
+        })
+    }
+
+    // The degree is the index in the held notes, which wraps by octave. Nothing held is a rest.
+    fn make_held_pitches(
+        degree: Vec<Degree>,
+        held: &[u8],
+        transpose: f64,
+        mtranspose: f64,
+    ) -> Vec<Degree> {
+        degree
+            .iter()
+            .map(|d| match d {
+                Degree::Pitch(p) if !held.is_empty() => {
+                    let index = (mtranspose + p).floor() as i64;
+                    let len = held.len() as i64;
+                    let note = held[index.rem_euclid(len) as usize] as f64;
+                    let oct = index.div_euclid(len) as f64 * 12.0;
+                    Degree::Pitch(note + oct + transpose)
+                }
+                _ => Degree::Rest,
+            })
+            .collect()
+    }
+
     fn make_pitches(
         &self,
         degree: Vec<Degree>,
@@ -107,6 +182,41 @@ impl TryFrom<&KMap> for Pattern {
         let octave = StreamF64::from_map(map, "octave", 5.0)?;
         let channel = StreamF64::from_map(map, "channel", 0.0)?;
         let amp = StreamF64::from_map(map, "amp", 0.85)?;
+        let restart_on_loop = bool_from_map(map, "restart_on_loop", false)?;
+        let quantization = match map.get("quant") {
+            Some(KValue::Null) | None => None,
+            Some(value) => {
+                Some(Quantization::try_from(&value).map_err(|e| Error::Other(format!("{}", e)))?)
+            }
+        };
+        let name = string_from_map(map, "name")?;
+        let held = bool_from_map(map, "held", false)?;
+        let tempo_ratio = match map.get("tempo_ratio") {
+            Some(KValue::Number(ratio)) if f64::from(ratio) > 0.0 => Some(f64::from(ratio)),
+            Some(KValue::Null) | None => None,
+            Some(value) => {
+                return Err(Error::ValueType(
+                    format!("{}", value.type_as_string()),
+                    "positive number".to_string(),
+                ))
+            }
+        };
+        let tempo_curve = match map.get("tempo_curve") {
+            Some(KValue::Null) | None => None,
+            Some(value) => {
+                Some(TempoCurve::try_from(&value).map_err(|e| Error::Other(format!("{}", e)))?)
+            }
+        };
+        let seed = match map.get("seed") {
+            Some(KValue::Number(seed)) => Some(f64::from(seed) as i64 as u64),
+            Some(KValue::Null) | None => None,
+            Some(value) => {
+                return Err(Error::ValueType(
+                    format!("{}", value.type_as_string()),
+                    "number".to_string(),
+                ))
+            }
+        };
 
         Ok(Self {
             dur,
@@ -119,10 +229,39 @@ impl TryFrom<&KMap> for Pattern {
             octave,
             channel,
             amp,
+            restart_on_loop,
+            quantization,
+            name,
+            tempo_ratio,
+            tempo_curve,
+            seed,
+            held,
         })
     }
 }
 
+fn bool_from_map(map: &KMap, key: &str, default: bool) -> Result<bool, Error> {
+    match map.get(key) {
+        Some(KValue::Bool(value)) => Ok(value),
+        Some(KValue::Null) | None => Ok(default),
+        Some(value) => Err(Error::ValueType(
+            format!("{}", value.type_as_string()),
+            "bool".to_string(),
+        )),
+    }
+}
+
+fn string_from_map(map: &KMap, key: &str) -> Result<Option<String>, Error> {
+    match map.get(key) {
+        Some(KValue::Str(value)) => Ok(Some(value.as_str().to_string())),
+        Some(KValue::Null) | None => Ok(None),
+        Some(value) => Err(Error::ValueType(
+            format!("{}", value.type_as_string()),
+            "string".to_string(),
+        )),
+    }
+}
+
 #[derive(Debug, Clone)]
 pub(crate) struct ScheduledEvent {
     pub(crate) position: f64,
@@ -138,7 +277,7 @@ pub(crate) struct Event {
     pub(crate) length: f64,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub(crate) enum EventValue {
     // note number, velocity, channel number
     // velocity == 0 is note-off
@@ -11,6 +11,22 @@ pub(super) trait Stream {
         Self: Sized;
 
     fn try_next(&mut self) -> Result<Option<Self::Item>, Error>;
+
+    /// Makes an independent copy of the stream, which starts from the current state of the original.
+    fn try_copy(&self) -> Result<Self, Error>
+    where
+        Self: Sized;
+}
+
+fn copy_iterator(iterator: &Option<KIterator>) -> Result<Option<KIterator>, Error> {
+    iterator
+        .as_ref()
+        .map(|iterator| {
+            iterator
+                .make_copy()
+                .map_err(|e| Error::Iterator(format!("{}", e)))
+        })
+        .transpose()
 }
 
 #[derive(Debug)]
@@ -77,6 +93,13 @@ impl Stream for StreamF64 {
                 .transpose()
         }
     }
+
+    fn try_copy(&self) -> Result<Self, Error> {
+        Ok(Self {
+            value: self.value,
+            iterator: copy_iterator(&self.iterator)?,
+        })
+    }
 }
 
 #[derive(Debug)]
@@ -180,6 +203,13 @@ impl Stream for StreamVecDegree {
             })
             .transpose()
     }
+
+    fn try_copy(&self) -> Result<Self, Error> {
+        Ok(Self {
+            value: self.value.clone(),
+            iterator: copy_iterator(&self.iterator)?,
+        })
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -257,6 +287,13 @@ impl Stream for StreamScale {
                 .transpose()
         }
     }
+
+    fn try_copy(&self) -> Result<Self, Error> {
+        Ok(Self {
+            value: self.value,
+            iterator: copy_iterator(&self.iterator)?,
+        })
+    }
 }
 
 #[derive(Debug, Error)]
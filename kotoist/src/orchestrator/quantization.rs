@@ -0,0 +1,216 @@
+use std::convert::TryFrom;
+
+use koto::runtime::KValue;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// The grid on which a pattern starts playing.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub(crate) struct Quantization {
+    pub(crate) value: f64,
+    pub(crate) unit: QuantizationUnit,
+    /// The position within the grid in beats, i.e. the pattern starts at the next multiple of
+    /// `value` plus `phase`.
+    pub(crate) phase: f64,
+    /// The timing offset in beats. Negative values make the pattern start earlier.
+    pub(crate) offset: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub(crate) enum QuantizationUnit {
+    Beats,
+    Bars,
+}
+
+impl Quantization {
+    pub(crate) fn beats(value: f64) -> Self {
+        Self {
+            value,
+            unit: QuantizationUnit::Beats,
+            phase: 0.0,
+            offset: 0.0,
+        }
+    }
+
+    pub(crate) fn bars(value: f64) -> Self {
+        Self {
+            value,
+            unit: QuantizationUnit::Bars,
+            phase: 0.0,
+            offset: 0.0,
+        }
+    }
+
+    pub(crate) fn with_phase(self, phase: f64) -> Self {
+        Self { phase, ..self }
+    }
+
+    pub(crate) fn with_offset(self, offset: f64) -> Self {
+        Self { offset, ..self }
+    }
+
+    /// The next point of the grid after the position. The bars are counted from `bar_origin`, the
+    /// start of the bar 0. The positions and the lengths are in the same units (samples or beats).
+    pub(crate) fn next_position(
+        &self,
+        position: f64,
+        beat_length: f64,
+        bar_origin: f64,
+        bar_length: f64,
+    ) -> f64 {
+        // without the grid the pattern starts right away, so only a delay can apply
+        if self.value == 0.0 {
+            return position + (self.offset * beat_length).max(0.0);
+        }
+        // the position from which the grid is counted
+        let (origin, quant_length) = match self.unit {
+            QuantizationUnit::Beats => (0.0, self.value * beat_length),
+            QuantizationUnit::Bars => (bar_origin, self.value * bar_length),
+        };
+        let shift = (self.phase + self.offset) * beat_length;
+        // the next grid point, which (being shifted) is still ahead of the current position
+        let grid_points = ((position - origin - shift) / quant_length).floor() + 1.0;
+        origin + shift + grid_points * quant_length
+    }
+}
+
+impl Default for Quantization {
+    fn default() -> Self {
+        Self::beats(0.0)
+    }
+}
+
+/// Parses strings like "4", "4beats", "1bar" or "2bars".
+impl TryFrom<&str> for Quantization {
+    type Error = QuantizationError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let trimmed = value.trim().to_lowercase();
+
+        let (number, unit) = ["bars", "bar", "beats", "beat"]
+            .iter()
+            .find_map(|suffix| trimmed.strip_suffix(suffix).map(|number| (number, *suffix)))
+            .unwrap_or((trimmed.as_str(), "beats"));
+
+        let number: f64 = number
+            .trim()
+            .parse()
+            .map_err(|_| QuantizationError::Unparsable(value.to_string()))?;
+
+        if number < 0.0 {
+            return Err(QuantizationError::Negative(value.to_string()));
+        }
+
+        match unit {
+            "bars" | "bar" => Ok(Self::bars(number)),
+            _ => Ok(Self::beats(number)),
+        }
+    }
+}
+
+/// Converts a number of beats, a string like "1bar" or a list `[quant, phase, offset]`, where
+/// `quant` is either a number or a string.
+impl TryFrom<&KValue> for Quantization {
+    type Error = QuantizationError;
+
+    fn try_from(value: &KValue) -> Result<Self, Self::Error> {
+        match value {
+            KValue::Number(quant) if f64::from(quant) < 0.0 => {
+                Err(QuantizationError::Negative(f64::from(quant).to_string()))
+            }
+            KValue::Number(quant) => Ok(Self::beats(f64::from(quant))),
+            KValue::Str(quant) => Self::try_from(quant.as_str()),
+            KValue::List(list) => match list.data().as_slice() {
+                [quant] => Self::grid(quant),
+                [quant, KValue::Number(phase)] => {
+                    Ok(Self::grid(quant)?.with_phase(f64::from(phase)))
+                }
+                [quant, KValue::Number(phase), KValue::Number(offset)] => Ok(Self::grid(quant)?
+                    .with_phase(f64::from(phase))
+                    .with_offset(f64::from(offset))),
+                _ => Err(QuantizationError::ValueType("list".to_string())),
+            },
+            other => Err(QuantizationError::ValueType(
+                other.type_as_string().to_string(),
+            )),
+        }
+    }
+}
+
+impl Quantization {
+    /// The `quant` element of the list, which can't be a list itself.
+    fn grid(value: &KValue) -> Result<Self, QuantizationError> {
+        match value {
+            KValue::Number(_) | KValue::Str(_) => Self::try_from(value),
+            other => Err(QuantizationError::ValueType(
+                other.type_as_string().to_string(),
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub(crate) enum QuantizationError {
+    #[error(
+        "Unexpected quantization '{0}', expected a number of beats, a string like \"1bar\" \
+            or a list [quant, phase, offset]."
+    )]
+    ValueType(String),
+    #[error(
+        "Can't parse quantization '{0}', expected a number of beats or bars, e.g. '4' or '1bar'."
+    )]
+    Unparsable(String),
+    #[error("The quantization '{0}' can't be negative.")]
+    Negative(String),
+}
+
+#[cfg(test)]
+mod test {
+    use koto::runtime::KList;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_quantization() {
+        assert_eq!(
+            Quantization::try_from("4").unwrap(),
+            Quantization::beats(4.0)
+        );
+        assert_eq!(
+            Quantization::try_from("1.5beats").unwrap(),
+            Quantization::beats(1.5)
+        );
+        assert_eq!(
+            Quantization::try_from("1bar").unwrap(),
+            Quantization::bars(1.0)
+        );
+        assert_eq!(
+            Quantization::try_from("2 Bars").unwrap(),
+            Quantization::bars(2.0)
+        );
+        assert!(Quantization::try_from("bar").is_err());
+        assert!(Quantization::try_from("-1bar").is_err());
+    }
+
+    #[test]
+    fn test_quantization_from_koto() {
+        let list = |items: &[KValue]| KValue::List(KList::from_slice(items));
+
+        assert_eq!(
+            Quantization::try_from(&list(&[
+                KValue::Str("1bar".into()),
+                0.5.into(),
+                (-0.25).into()
+            ]))
+            .unwrap(),
+            Quantization::bars(1.0).with_phase(0.5).with_offset(-0.25)
+        );
+        assert_eq!(
+            Quantization::try_from(&list(&[4.0.into()])).unwrap(),
+            Quantization::beats(4.0)
+        );
+        // the nested lists aren't accepted
+        assert!(Quantization::try_from(&list(&[list(&[4.0.into()])])).is_err());
+        assert!(Quantization::try_from(&list(&[list(&[4.0.into()]), 0.5.into()])).is_err());
+    }
+}
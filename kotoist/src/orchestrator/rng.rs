@@ -0,0 +1,75 @@
+//! The random numbers of the players.
+//!
+//! Each player has its own generator, seeded from the pattern, so the generative output is the
+//! same each time the pattern is played. The generator of the player is made current for the time
+//! its pattern is pulled, so the `random` module of the scripts draws from it as well.
+use std::cell::RefCell;
+
+use fastrand::Rng;
+
+thread_local! {
+    static CURRENT: RefCell<Option<Rng>> = const { RefCell::new(None) };
+}
+
+/// Calls the function with the generator made current, the state is kept in `rng` afterwards.
+pub(crate) fn with_rng<T>(rng: &mut Rng, f: impl FnOnce() -> T) -> T {
+    CURRENT.with(|current| *current.borrow_mut() = Some(rng.clone()));
+    let result = f();
+    if let Some(current) = CURRENT.with(|current| current.borrow_mut().take()) {
+        *rng = current;
+    }
+
+    result
+}
+
+/// A number in the range `[0, 1)`, `None` if there's no current generator.
+pub(crate) fn number() -> Option<f64> {
+    CURRENT.with(|current| current.borrow_mut().as_mut().map(|rng| rng.f64()))
+}
+
+/// An index in the range `[0, len)`, `None` if there's no current generator.
+pub(crate) fn index(len: usize) -> Option<usize> {
+    CURRENT.with(|current| current.borrow_mut().as_mut().map(|rng| rng.usize(..len)))
+}
+
+/// The seed of the player, which doesn't set it in the pattern. The name is mixed in, so the
+/// players don't repeat each other.
+pub(crate) fn player_seed(seed: u64, name: &str) -> u64 {
+    // FNV-1a with the seed as the offset basis
+    name.bytes()
+        .fold(seed ^ 0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        })
+}
+
+/// The seed of the density dropouts of the player. They have their own generator, so the density
+/// doesn't change the values of the pattern.
+pub(crate) fn density_seed(seed: u64) -> u64 {
+    player_seed(seed, "density")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_with_rng() {
+        assert_eq!(number(), None);
+
+        let mut rng = Rng::with_seed(player_seed(1, "0"));
+        let first: Vec<f64> = with_rng(&mut rng.clone(), || {
+            (0..4).filter_map(|_| number()).collect()
+        });
+        let second: Vec<f64> = with_rng(&mut rng, || (0..4).filter_map(|_| number()).collect());
+        assert_eq!(first.len(), 4);
+        assert_eq!(first, second);
+
+        // the state is kept between the calls
+        let third: Vec<f64> = with_rng(&mut rng, || (0..4).filter_map(|_| number()).collect());
+        assert_ne!(second, third);
+        assert_eq!(number(), None);
+
+        assert_ne!(player_seed(1, "0"), player_seed(1, "1"));
+        assert_ne!(player_seed(1, "0"), player_seed(2, "0"));
+    }
+}
@@ -0,0 +1,315 @@
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+
+use koto::runtime::{KIterator, KIteratorOutput, KValue};
+use thiserror::Error;
+
+/// The tempo envelope of a player. The ratio is relative to the host tempo and it's evaluated in
+/// the player's beats, counted from the moment the curve is set.
+#[derive(Debug)]
+pub(crate) enum TempoCurve {
+    /// An iterator yielding a ratio per beat. The last ratio is held, when the iterator ends.
+    Steps(Steps),
+    /// A list of `(beat, ratio)` pairs, the ratio is interpolated linearly between them and held
+    /// before the first and after the last one.
+    Breakpoints(Vec<(f64, f64)>),
+}
+
+impl TempoCurve {
+    /// Integrates the curve, i.e. returns the number of host beats between the player's beats
+    /// `from` and `to`.
+    pub(crate) fn host_beats(&mut self, from: f64, to: f64) -> Result<f64, TempoCurveError> {
+        if to <= from {
+            return Ok(0.0);
+        }
+
+        match self {
+            Self::Steps(steps) => steps.host_beats(from, to),
+            Self::Breakpoints(points) => Ok(integrate_breakpoints(points, from, to)),
+        }
+    }
+
+    /// Makes an independent copy of the curve, which starts from the current state of the original.
+    pub(crate) fn try_copy(&self) -> Result<Self, TempoCurveError> {
+        match self {
+            Self::Steps(steps) => Ok(Self::Steps(Steps {
+                iterator: steps
+                    .iterator
+                    .make_copy()
+                    .map_err(|e| TempoCurveError::Iterator(format!("{}", e)))?,
+                ratios: steps.ratios.clone(),
+                first_beat: steps.first_beat,
+                finished: steps.finished,
+            })),
+            Self::Breakpoints(points) => Ok(Self::Breakpoints(points.clone())),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Steps {
+    iterator: KIterator,
+    ratios: VecDeque<f64>,
+    // the beat of the first cached ratio
+    first_beat: usize,
+    finished: bool,
+}
+
+impl Steps {
+    fn new(iterator: KIterator) -> Self {
+        Self {
+            iterator,
+            ratios: VecDeque::new(),
+            first_beat: 0,
+            finished: false,
+        }
+    }
+
+    fn host_beats(&mut self, from: f64, to: f64) -> Result<f64, TempoCurveError> {
+        self.forget_before(from.floor() as usize);
+
+        let mut result = 0.0;
+        let mut start = from;
+        while start < to {
+            let beat = start.floor();
+            let end = (beat + 1.0).min(to);
+            result += (end - start) / self.ratio_at(beat as usize)?;
+            start = end;
+        }
+
+        Ok(result)
+    }
+
+    fn ratio_at(&mut self, beat: usize) -> Result<f64, TempoCurveError> {
+        while !self.finished && self.first_beat + self.ratios.len() <= beat {
+            match self.iterator.next() {
+                Some(KIteratorOutput::Value(KValue::Number(ratio))) if f64::from(ratio) > 0.0 => {
+                    self.ratios.push_back(f64::from(ratio))
+                }
+                Some(KIteratorOutput::Value(other)) => {
+                    return Err(TempoCurveError::Ratio(other.type_as_string().to_string()))
+                }
+                Some(KIteratorOutput::ValuePair(_, _)) => {
+                    return Err(TempoCurveError::Ratio("value pair".to_string()))
+                }
+                Some(KIteratorOutput::Error(e)) => {
+                    return Err(TempoCurveError::Iterator(format!("{}", e)))
+                }
+                None => self.finished = true,
+            }
+        }
+
+        Ok(self
+            .ratios
+            .get(beat - self.first_beat)
+            .or_else(|| self.ratios.back())
+            .copied()
+            .unwrap_or(1.0))
+    }
+
+    // the ratios before the beat won't be needed anymore, as the player doesn't go backwards
+    fn forget_before(&mut self, beat: usize) {
+        // keep at least one ratio to hold it, when the iterator ends
+        while self.first_beat < beat && self.ratios.len() > 1 {
+            self.ratios.pop_front();
+            self.first_beat += 1;
+        }
+    }
+}
+
+// the integral of 1 / ratio over the piecewise linear curve
+fn integrate_breakpoints(points: &[(f64, f64)], from: f64, to: f64) -> f64 {
+    let (first_beat, first_ratio) = points[0];
+    let (last_beat, last_ratio) = points[points.len() - 1];
+    let mut result = 0.0;
+
+    // before the first breakpoint
+    if from < first_beat {
+        result += (to.min(first_beat) - from) / first_ratio;
+    }
+
+    for window in points.windows(2) {
+        let (b0, r0) = window[0];
+        let (b1, r1) = window[1];
+        let start = from.max(b0);
+        let end = to.min(b1);
+        if end <= start {
+            continue;
+        }
+
+        let slope = (r1 - r0) / (b1 - b0);
+        let ratio = |beat: f64| r0 + slope * (beat - b0);
+        result += if slope.abs() < 1e-12 {
+            (end - start) / r0
+        } else {
+            (ratio(end) / ratio(start)).ln() / slope
+        };
+    }
+
+    // after the last breakpoint
+    if to > last_beat {
+        result += (to - from.max(last_beat)) / last_ratio;
+    }
+
+    result
+}
+
+/// Converts an iterator of ratios per beat or a list of `[beat, ratio]` breakpoints.
+impl TryFrom<&KValue> for TempoCurve {
+    type Error = TempoCurveError;
+
+    fn try_from(value: &KValue) -> Result<Self, Self::Error> {
+        match value {
+            KValue::Iterator(iterator) => Ok(Self::Steps(Steps::new(iterator.clone()))),
+            KValue::List(list) => {
+                let mut points: Vec<(f64, f64)> = Vec::new();
+
+                for item in list.data().iter() {
+                    let point = match item {
+                        KValue::List(point) => match point.data().as_slice() {
+                            [KValue::Number(beat), KValue::Number(ratio)] => {
+                                (f64::from(beat), f64::from(ratio))
+                            }
+                            _ => return Err(TempoCurveError::Breakpoint),
+                        },
+                        _ => return Err(TempoCurveError::Breakpoint),
+                    };
+
+                    if point.1 <= 0.0 {
+                        return Err(TempoCurveError::Ratio(point.1.to_string()));
+                    }
+                    if points.last().is_some_and(|last| last.0 > point.0) {
+                        return Err(TempoCurveError::Order);
+                    }
+
+                    points.push(point);
+                }
+
+                if points.is_empty() {
+                    return Err(TempoCurveError::Breakpoint);
+                }
+
+                Ok(Self::Breakpoints(points))
+            }
+            other => Err(TempoCurveError::ValueType(
+                other.type_as_string().to_string(),
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub(crate) enum TempoCurveError {
+    #[error(
+        "Unexpected tempo curve '{0}', expected an iterator of ratios or a list of \
+            [beat, ratio] breakpoints."
+    )]
+    ValueType(String),
+    #[error("The tempo curve breakpoints should be a non-empty list of [beat, ratio] pairs.")]
+    Breakpoint,
+    #[error("The tempo curve breakpoints should be sorted by beat.")]
+    Order,
+    #[error("Unexpected tempo ratio '{0}', expected a positive number.")]
+    Ratio(String),
+    #[error("Error processing tempo curve iterator: '{0}'")]
+    Iterator(String),
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use koto::runtime::{KotoIterator, Result as KotoResult};
+
+    use super::*;
+
+    /// Yields the ratios and counts the pulls, the copies share the counter.
+    #[derive(Clone)]
+    struct Ratios {
+        ratios: Vec<KValue>,
+        position: usize,
+        pulls: Arc<AtomicUsize>,
+    }
+
+    impl KotoIterator for Ratios {
+        fn make_copy(&self) -> KotoResult<KIterator> {
+            Ok(KIterator::new(self.clone()))
+        }
+    }
+
+    impl Iterator for Ratios {
+        type Item = KIteratorOutput;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            let ratio = self.ratios.get(self.position)?.clone();
+            self.position += 1;
+            self.pulls.fetch_add(1, Ordering::Relaxed);
+            Some(KIteratorOutput::Value(ratio))
+        }
+    }
+
+    fn steps(ratios: Vec<KValue>) -> (TempoCurve, Arc<AtomicUsize>) {
+        let pulls = Arc::new(AtomicUsize::new(0));
+        let iterator = KIterator::new(Ratios {
+            ratios,
+            position: 0,
+            pulls: pulls.clone(),
+        });
+
+        (
+            TempoCurve::try_from(&KValue::Iterator(iterator)).unwrap(),
+            pulls,
+        )
+    }
+
+    fn assert_approx(left: f64, right: f64) {
+        assert!((left - right).abs() < 1e-9, "{} != {}", left, right);
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut constant = TempoCurve::Breakpoints(vec![(0.0, 2.0)]);
+        assert_approx(constant.host_beats(0.0, 4.0).unwrap(), 2.0);
+
+        // accelerando from the host tempo to the double tempo over 4 beats
+        let mut curve = TempoCurve::Breakpoints(vec![(0.0, 1.0), (4.0, 2.0)]);
+        assert_approx(curve.host_beats(0.0, 4.0).unwrap(), 4.0 * 2f64.ln());
+        // the last ratio is held
+        assert_approx(curve.host_beats(4.0, 6.0).unwrap(), 1.0);
+        // and the first one is held before the curve
+        let mut delayed = TempoCurve::Breakpoints(vec![(2.0, 0.5), (4.0, 0.5)]);
+        assert_approx(delayed.host_beats(0.0, 1.0).unwrap(), 2.0);
+        assert_approx(
+            curve.host_beats(1.0, 3.0).unwrap(),
+            curve.host_beats(1.0, 2.0).unwrap() + curve.host_beats(2.0, 3.0).unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_steps() {
+        let (mut curve, pulls) = steps(vec![1.0.into(), 2.0.into(), 4.0.into()]);
+        assert_approx(curve.host_beats(0.0, 0.5).unwrap(), 0.5);
+        // the ratio of the beat is cached
+        assert_approx(curve.host_beats(0.5, 1.0).unwrap(), 0.5);
+        assert_eq!(pulls.load(Ordering::Relaxed), 1);
+        // the beats are integrated with their own ratios
+        assert_approx(curve.host_beats(1.0, 2.5).unwrap(), 0.5 + 0.125);
+        assert_eq!(pulls.load(Ordering::Relaxed), 3);
+
+        // the copy continues from the current state independently
+        let mut copy = curve.try_copy().unwrap();
+        assert_approx(copy.host_beats(2.5, 3.0).unwrap(), 0.125);
+        // the last ratio is held, when the iterator ends
+        assert_approx(copy.host_beats(3.0, 5.0).unwrap(), 0.5);
+        assert_approx(curve.host_beats(2.5, 5.0).unwrap(), 0.625);
+
+        let (mut invalid, _) = steps(vec![1.0.into(), KValue::Str("fast".into())]);
+        assert_approx(invalid.host_beats(0.0, 1.0).unwrap(), 1.0);
+        assert!(invalid.host_beats(1.0, 2.0).is_err());
+        let (mut negative, _) = steps(vec![(-1.0).into()]);
+        assert!(negative.host_beats(0.0, 1.0).is_err());
+    }
+}
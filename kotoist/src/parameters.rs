@@ -8,10 +8,15 @@ use nih_plug::prelude::*;
 use nih_plug_egui::EguiState;
 use serde::{Deserialize, Serialize};
 
+use crate::automation::{MacroParams, MacroValues, NUM_MACROS};
+use crate::clock::Clock;
 use crate::editor::{PianoRollEvent, WINDOW_SIZE};
-use crate::interpreter::Interpreter;
-use crate::orchestrator::Orchestrator;
+use crate::interpreter::{Interpreter, SharedState};
+use crate::midi::{MidiIn, MidiState};
+use crate::orchestrator::{Modifiers, Orchestrator};
 use crate::pipe::PipeIn;
+use crate::scene::{SceneBinding, SceneQueue, SceneTrigger};
+use crate::status::PlaybackStatus;
 
 #[derive(Params)]
 pub(crate) struct Parameters {
@@ -20,47 +25,112 @@ pub(crate) struct Parameters {
     cursor_in_beats: Arc<AtomicF32>,
     gl_context_valid: Arc<AtomicBool>,
     pub(crate) orchestrator: Arc<Mutex<Orchestrator>>,
+    pub(crate) clock: Arc<Clock>,
+    pub(crate) midi_state: Arc<MidiState>,
+    pub(crate) macro_values: Arc<MacroValues>,
+    pub(crate) playback: Arc<PlaybackStatus>,
+    #[id = "midi-thru"]
+    pub(crate) midi_thru: BoolParam,
+    #[id = "transpose"]
+    pub(crate) transpose: IntParam,
+    #[id = "velocity-scale"]
+    pub(crate) velocity_scale: FloatParam,
+    #[id = "mute"]
+    pub(crate) mute: BoolParam,
+    #[id = "density"]
+    pub(crate) density: FloatParam,
+    #[id = "scene"]
+    pub(crate) scene: IntParam,
+    #[nested(array, group = "Macros")]
+    pub(crate) macros: [MacroParams; NUM_MACROS],
     #[persist = "editor-state"]
     pub(crate) editor_state: Arc<EguiState>,
     #[persist = "selected-snippet"]
     pub(crate) selected_snippet: AtomicUsize,
     #[persist = "snippets"]
     pub(crate) snippets: Arc<RwLock<Vec<Snippet>>>,
+    /// The seed of the random numbers of the players, kept so the project plays the same.
+    #[persist = "seed"]
+    pub(crate) seed: Arc<RwLock<u64>>,
 }
 
 impl Parameters {
     pub(crate) fn new(
         pipe_in: PipeIn,
         piano_roll_sender: mpsc::Sender<Vec<PianoRollEvent>>,
+        midi_in: MidiIn,
     ) -> Self {
-        let orchestrator = Arc::new(Mutex::new(Orchestrator::new(pipe_in.clone())));
+        let midi_state = Arc::new(MidiState::default());
+        let orchestrator = Arc::new(Mutex::new(Orchestrator::new(
+            pipe_in.clone(),
+            midi_state.clone(),
+        )));
         // there always should be at least one snippet
         let snippets = Arc::new(RwLock::new(vec![Snippet::with_random_name()]));
-        let interpreter_sender =
-            Self::spawn_interpreter_worker(orchestrator.clone(), snippets.clone(), pipe_in);
+        let clock = Arc::new(Clock::default());
+        let macro_values = Arc::new(MacroValues::default());
+        let cursor_in_beats: Arc<AtomicF32> = Default::default();
+        let playback = Arc::new(PlaybackStatus::default());
+        let seed = Arc::new(RwLock::new(fastrand::u64(..)));
+        let interpreter_sender = Self::spawn_interpreter_worker(
+            SharedState {
+                orchestrator: orchestrator.clone(),
+                snippets: snippets.clone(),
+                clock: clock.clone(),
+                midi_in,
+                midi_state: midi_state.clone(),
+                macro_values: macro_values.clone(),
+                cursor: cursor_in_beats.clone(),
+                playback: playback.clone(),
+                seed: seed.clone(),
+            },
+            pipe_in,
+        );
+        let midi_thru = BoolParam::new("MIDI Thru", false).with_callback({
+            let midi_state = midi_state.clone();
+            Arc::new(move |value| midi_state.thru.set_enabled(value))
+        });
 
         Self {
             interpreter_sender,
-            cursor_in_beats: Default::default(),
+            cursor_in_beats,
             orchestrator: orchestrator.clone(),
+            clock,
+            midi_state,
+            midi_thru,
+            transpose: IntParam::new("Transpose", 0, IntRange::Linear { min: -48, max: 48 })
+                .with_unit(" st"),
+            velocity_scale: FloatParam::new(
+                "Velocity Scale",
+                1.0,
+                FloatRange::Linear { min: 0.0, max: 2.0 },
+            ),
+            mute: BoolParam::new("Mute", false),
+            density: FloatParam::new("Density", 1.0, FloatRange::Linear { min: 0.0, max: 1.0 }),
+            scene: IntParam::new("Scene", 0, IntRange::Linear { min: 0, max: 127 }),
+            macros: MacroParams::bank(macro_values.clone()),
+            macro_values,
+            playback,
             selected_snippet: Default::default(),
             piano_roll_sender,
             gl_context_valid: Default::default(),
             snippets,
+            seed,
             editor_state: EguiState::from_size(WINDOW_SIZE.0, WINDOW_SIZE.1),
         }
     }
 
     fn spawn_interpreter_worker(
-        orchestrator: Arc<Mutex<Orchestrator>>,
-        snippets: Arc<RwLock<Vec<Snippet>>>,
+        shared: SharedState,
         pipe_in: PipeIn,
     ) -> mpsc::Sender<InterpreterMessage> {
         let (interpreter_sender, interpreter_receiver) = mpsc::channel();
 
         thread::spawn(move || {
-            let mut interp = Interpreter::new(orchestrator, pipe_in.clone());
+            let snippets = shared.snippets.clone();
+            let mut interp = Interpreter::new(shared, pipe_in);
             let mut is_playing = false;
+            let mut scenes = SceneQueue::default();
             loop {
                 if let Ok(message) = interpreter_receiver.recv() {
                     match message {
@@ -72,28 +142,113 @@ impl Parameters {
 
                         InterpreterMessage::EvalCode(code) => interp.eval_code(&code),
 
+                        InterpreterMessage::SeedRandom => interp.seed_random(),
+
                         InterpreterMessage::OnLoad => interp.on_load(),
 
-                        InterpreterMessage::OnMidiIn(nn, vel, ch) => interp.on_midiin(nn, vel, ch),
+                        InterpreterMessage::OnMidiIn(nn, vel, ch, beat_pos) => {
+                            interp.on_midiin(nn, vel, ch, beat_pos);
+                            if vel > 0.0 {
+                                trigger_scene(
+                                    &mut interp,
+                                    &mut scenes,
+                                    &snippets,
+                                    SceneTrigger::Note(nn),
+                                );
+                            }
+                        }
 
                         InterpreterMessage::OnMidiInCc(cc, vel, ch) => {
                             interp.on_midiincc(cc, vel, ch)
                         }
 
+                        InterpreterMessage::OnPitchBend(value, ch) => {
+                            interp.on_pitchbend(value, ch)
+                        }
+
+                        InterpreterMessage::OnAftertouch(pressure, ch, nn) => {
+                            interp.on_aftertouch(pressure, ch, nn)
+                        }
+
+                        InterpreterMessage::OnProgramChange(program, ch) => {
+                            interp.on_program_change(program, ch);
+                            trigger_scene(
+                                &mut interp,
+                                &mut scenes,
+                                &snippets,
+                                SceneTrigger::Program(program),
+                            );
+                        }
+
+                        InterpreterMessage::OnScene(scene) => {
+                            trigger_scene(
+                                &mut interp,
+                                &mut scenes,
+                                &snippets,
+                                SceneTrigger::Scene(scene),
+                            );
+                        }
+
+                        InterpreterMessage::OnClock(tick, beat_pos) => {
+                            interp.on_clock(tick, beat_pos);
+                            interp.run_scheduled(beat_pos);
+                            for code in scenes.on_clock(beat_pos) {
+                                interp.eval_code(&code);
+                            }
+                        }
+
+                        InterpreterMessage::OnSysEx(bytes) => interp.on_sysex(&bytes),
+
+                        InterpreterMessage::OnMacro(index, value) => interp.on_macro(index, value),
+
                         InterpreterMessage::OnPause(beat_pos, tempo) => {
+                            scenes.set_playing(false);
                             if is_playing {
                                 is_playing = false;
                                 interp.on_pause(beat_pos, tempo);
+                                interp.on_transport(false, beat_pos, tempo);
                             }
                         }
 
                         InterpreterMessage::OnPlay(beat_pos, tempo) => {
+                            // it's sent once per block, while the transport is playing
+                            interp.prefetch_events();
+                            interp.prepare_restarts();
+                            scenes.set_playing(true);
                             if !is_playing {
                                 is_playing = true;
+                                // the position could be moved, while the transport was paused
+                                interp.jump_scheduled(beat_pos);
                                 interp.on_play(beat_pos, tempo);
+                                interp.on_transport(true, beat_pos, tempo);
                             }
                         }
 
+                        InterpreterMessage::OnStop(beat_pos) => {
+                            interp.jump_scheduled(beat_pos);
+                            interp.on_stop(beat_pos)
+                        }
+
+                        InterpreterMessage::OnTempoChange(old_tempo, new_tempo) => {
+                            interp.on_tempo_change(old_tempo, new_tempo)
+                        }
+
+                        InterpreterMessage::OnJump(from_beat_pos, to_beat_pos) => {
+                            interp.jump_scheduled(to_beat_pos);
+                            interp.on_jump(from_beat_pos, to_beat_pos)
+                        }
+
+                        InterpreterMessage::OnBar(bar, beat_pos, bar_start, bar_length) => {
+                            interp.on_bar(bar, beat_pos);
+                            for code in scenes.on_bar(bar, bar_start, bar_length) {
+                                interp.eval_code(&code);
+                            }
+                        }
+
+                        InterpreterMessage::OnBeat(beat, bar, beat_pos) => {
+                            interp.on_beat(beat, bar, beat_pos)
+                        }
+
                         InterpreterMessage::AddSnippet => {
                             let mut snippets = snippets.write().unwrap();
                             snippets.push(Snippet::with_random_name());
@@ -113,6 +268,16 @@ impl Parameters {
         interpreter_sender
     }
 
+    /// The modifications of the generated events set by the plugin parameters.
+    pub(crate) fn modifiers(&self) -> Modifiers {
+        Modifiers {
+            transpose: self.transpose.value(),
+            velocity_scale: self.velocity_scale.value(),
+            mute: self.mute.value(),
+            density: self.density.value(),
+        }
+    }
+
     pub(crate) fn clone_cursor(&self) -> Arc<AtomicF32> {
         self.cursor_in_beats.clone()
     }
@@ -159,16 +324,52 @@ impl Parameters {
     }
 }
 
+// Evaluates the snippets bound to the trigger or queues them until the quantization boundary.
+fn trigger_scene(
+    interp: &mut Interpreter,
+    scenes: &mut SceneQueue,
+    snippets: &RwLock<Vec<Snippet>>,
+    trigger: SceneTrigger,
+) {
+    let codes = scenes.trigger(trigger, &snippets.read().unwrap());
+    for code in codes {
+        interp.eval_code(&code);
+    }
+}
+
 #[derive(Debug, Clone)]
 pub(crate) enum InterpreterMessage {
     // EvalSnippet(Uuid),
     SetSnippetCode(usize, String),
     EvalCode(String),
+    // seeds the `random` module with the global seed, before the project is evaluated
+    SeedRandom,
     OnLoad,
-    OnMidiIn(u8, f32, u8),
+    OnMidiIn(u8, f32, u8, f64),
     OnMidiInCc(u8, f32, u8),
+    // the bend from -1.0 to 1.0, channel
+    OnPitchBend(f32, u8),
+    // pressure, channel, note (none for the channel pressure)
+    OnAftertouch(f32, u8, Option<u8>),
+    OnProgramChange(u8, u8),
+    // the value of the scene parameter
+    OnScene(i32),
+    // the tick at 24 PPQ, beat position
+    OnClock(i64, f64),
+    OnSysEx(Vec<u8>),
+    // the macro index (from 0), value
+    OnMacro(usize, f32),
     OnPause(f64, f64),
     OnPlay(f64, f64),
+    // the beat position
+    OnStop(f64),
+    // the old and the new tempo
+    OnTempoChange(f64, f64),
+    // the beat positions before and after the jump
+    OnJump(f64, f64),
+    // the bar number, beat position, the start and the length of the bar in beats
+    OnBar(i64, f64, f64, f64),
+    OnBeat(u32, i64, f64),
     AddSnippet,
     RemoveSnippet(usize),
 }
@@ -177,6 +378,9 @@ pub(crate) enum InterpreterMessage {
 pub(crate) struct Snippet {
     pub(crate) code: String,
     pub(crate) name: String,
+    /// The trigger, which evaluates the snippet.
+    #[serde(default)]
+    pub(crate) binding: Option<SceneBinding>,
 }
 
 const NAME_SYMBOLS: [&str; 28] = [
@@ -192,6 +396,7 @@ impl Snippet {
         Self {
             name,
             code: String::new(),
+            binding: None,
         }
     }
 }
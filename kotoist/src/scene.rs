@@ -0,0 +1,182 @@
+//! Switching of the scenes, i.e. evaluation of the snippets bound to MIDI or automation triggers.
+use serde::{Deserialize, Serialize};
+
+use crate::orchestrator::Quantization;
+use crate::parameters::Snippet;
+
+/// What makes the bound snippet evaluate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub(crate) enum SceneTrigger {
+    /// The incoming program change (on any channel).
+    Program(u8),
+    /// The incoming note-on (on any channel).
+    Note(u8),
+    /// The value of the "Scene" plugin parameter.
+    Scene(i32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub(crate) struct SceneBinding {
+    pub(crate) trigger: SceneTrigger,
+    /// The grid, on which the snippet is evaluated after the trigger.
+    pub(crate) quantization: Quantization,
+}
+
+/// The triggered snippets waiting for their quantization boundary. It's owned by the interpreter
+/// worker and driven by the bar and clock messages.
+#[derive(Debug, Default)]
+pub(crate) struct SceneQueue {
+    pending: Vec<PendingScene>,
+    // the latest beat position reported by the clock messages
+    position: f64,
+    // the start of the bar 0 and the bar length in beats reported by the bar messages
+    bars: Option<(f64, f64)>,
+    is_playing: bool,
+}
+
+#[derive(Debug)]
+struct PendingScene {
+    code: String,
+    // the beat position
+    due: f64,
+}
+
+impl SceneQueue {
+    /// Queues the snippets bound to the trigger. Returns the code, which should be evaluated right
+    /// away (when the transport is stopped or there's no quantization).
+    pub(crate) fn trigger(&mut self, trigger: SceneTrigger, snippets: &[Snippet]) -> Vec<String> {
+        let mut immediate = Vec::new();
+
+        for (binding, code) in snippets.iter().filter_map(|snippet| {
+            snippet
+                .binding
+                .filter(|binding| binding.trigger == trigger)
+                .map(|binding| (binding, snippet.code.clone()))
+        }) {
+            let quant = binding.quantization;
+
+            if !self.is_playing || quant.value == 0.0 {
+                immediate.push(code);
+                continue;
+            }
+
+            // in 4/4 from the beginning, until the first bar is reported
+            let (bar_origin, bar_length) = self.bars.unwrap_or((0.0, 4.0));
+            let due = quant.next_position(self.position, 1.0, bar_origin, bar_length);
+            self.pending.push(PendingScene { code, due });
+        }
+
+        immediate
+    }
+
+    pub(crate) fn set_playing(&mut self, is_playing: bool) {
+        self.is_playing = is_playing;
+    }
+
+    /// Takes the code of the scenes due at the beat position.
+    pub(crate) fn on_clock(&mut self, position: f64) -> Vec<String> {
+        self.position = position;
+        self.take_due(position)
+    }
+
+    /// Updates the bar grid from the start of the bar and the bar length (in beats) and takes the
+    /// code of the scenes due at the start of the bar.
+    pub(crate) fn on_bar(&mut self, bar: i64, bar_start: f64, bar_length: f64) -> Vec<String> {
+        self.bars = Some((bar_start - bar as f64 * bar_length, bar_length));
+        self.take_due(bar_start)
+    }
+
+    fn take_due(&mut self, position: f64) -> Vec<String> {
+        // the clock messages are sent at the ticks, so the boundary can be slightly passed
+        let (due, pending): (Vec<PendingScene>, Vec<PendingScene>) = self
+            .pending
+            .drain(..)
+            .partition(|scene| position >= scene.due - 1e-6);
+        self.pending = pending;
+
+        due.into_iter().map(|scene| scene.code).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn snippet(code: &str, trigger: SceneTrigger, quantization: Quantization) -> Snippet {
+        Snippet {
+            code: code.to_string(),
+            binding: Some(SceneBinding {
+                trigger,
+                quantization,
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_scene_queue() {
+        let snippets = vec![
+            snippet("a", SceneTrigger::Program(1), Quantization::beats(4.0)),
+            snippet("b", SceneTrigger::Note(36), Quantization::bars(2.0)),
+            snippet("c", SceneTrigger::Scene(1), Quantization::beats(0.0)),
+            Snippet::default(),
+        ];
+        let mut queue = SceneQueue::default();
+
+        // nothing to wait for, when the transport is stopped
+        assert_eq!(queue.trigger(SceneTrigger::Program(1), &snippets), ["a"]);
+
+        queue.set_playing(true);
+        assert!(queue.on_clock(5.0).is_empty());
+        assert!(queue
+            .trigger(SceneTrigger::Program(1), &snippets)
+            .is_empty());
+        assert!(queue.trigger(SceneTrigger::Note(36), &snippets).is_empty());
+        assert_eq!(queue.trigger(SceneTrigger::Scene(1), &snippets), ["c"]);
+        assert!(queue.trigger(SceneTrigger::Scene(2), &snippets).is_empty());
+
+        // the bars are in 4/4 from the beginning, until a bar is reported
+        assert!(queue.on_clock(7.9).is_empty());
+        assert_eq!(queue.on_clock(8.0), ["a", "b"]);
+
+        // the bar messages take the scenes due at the bar start too
+        assert!(queue.trigger(SceneTrigger::Note(36), &snippets).is_empty());
+        assert!(queue.on_bar(3, 12.0, 4.0).is_empty());
+        assert_eq!(queue.on_bar(4, 16.0, 4.0), ["b"]);
+    }
+
+    #[test]
+    fn test_scene_bar_grid() {
+        let snippets = vec![
+            snippet("a", SceneTrigger::Note(36), Quantization::bars(1.5)),
+            snippet(
+                "b",
+                SceneTrigger::Note(38),
+                Quantization::bars(1.0).with_phase(1.0),
+            ),
+            snippet(
+                "c",
+                SceneTrigger::Note(40),
+                Quantization::bars(1.0).with_offset(-0.5),
+            ),
+        ];
+        let mut queue = SceneQueue::default();
+        queue.set_playing(true);
+        // in 3/4, the bar 0 starts at the beat 1
+        assert!(queue.on_bar(2, 7.0, 3.0).is_empty());
+        assert!(queue.on_clock(7.5).is_empty());
+
+        for note in [36, 38, 40] {
+            assert!(queue
+                .trigger(SceneTrigger::Note(note), &snippets)
+                .is_empty());
+        }
+        // the phase and the offset shift the bar grid, one and a half bars is counted from the
+        // bar 0
+        assert!(queue.on_clock(7.9).is_empty());
+        assert_eq!(queue.on_clock(8.0), ["b"]);
+        assert!(queue.on_clock(9.4).is_empty());
+        assert_eq!(queue.on_clock(9.5), ["c"]);
+        assert_eq!(queue.on_clock(10.0), ["a"]);
+    }
+}
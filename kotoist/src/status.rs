@@ -0,0 +1,66 @@
+//! The playback state published by the audio thread, so the interpreter can read it without
+//! locking the orchestrator.
+use std::sync::{
+    atomic::{AtomicBool, AtomicU32, Ordering},
+    Mutex,
+};
+
+use nih_plug::prelude::AtomicF32;
+
+use crate::orchestrator::PlayerStatus;
+
+pub(crate) struct PlaybackStatus {
+    tempo: AtomicF32,
+    is_playing: AtomicBool,
+    // the numerator in the high half, the denominator in the low one
+    time_signature: AtomicU32,
+    // it's set and read by the interpreter only, the audio thread updates the playback state of
+    // the players through their status
+    players: Mutex<Vec<PlayerStatus>>,
+}
+
+impl Default for PlaybackStatus {
+    fn default() -> Self {
+        Self {
+            tempo: AtomicF32::new(120.0),
+            is_playing: AtomicBool::new(false),
+            time_signature: AtomicU32::new((4 << 16) | 4),
+            players: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl PlaybackStatus {
+    /// Should be called by the audio thread once per block.
+    pub(crate) fn set_transport(&self, tempo: f64, is_playing: bool, time_signature: (u32, u32)) {
+        let (numerator, denominator) = time_signature;
+        self.tempo.store(tempo as f32, Ordering::Relaxed);
+        self.is_playing.store(is_playing, Ordering::Relaxed);
+        self.time_signature.store(
+            (numerator.min(0xFFFF) << 16) | denominator.min(0xFFFF),
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Publishes the players. Should be called by the interpreter, when it sets the patterns.
+    pub(crate) fn set_players(&self, players: Vec<PlayerStatus>) {
+        *self.players.lock().unwrap() = players;
+    }
+
+    pub(crate) fn tempo(&self) -> f32 {
+        self.tempo.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn is_playing(&self) -> bool {
+        self.is_playing.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn time_signature(&self) -> (u32, u32) {
+        let value = self.time_signature.load(Ordering::Relaxed);
+        (value >> 16, value & 0xFFFF)
+    }
+
+    pub(crate) fn players(&self) -> Vec<PlayerStatus> {
+        self.players.lock().unwrap().clone()
+    }
+}